@@ -27,16 +27,43 @@ typedef enum {
   weval_req_arg_i64 = 1,
   weval_req_arg_f32 = 2,
   weval_req_arg_f64 = 3,
+  /* A pointer to `len` bytes at `data`, which weval treats as constant
+   * (like `weval_assume_const_memory`): they must not change once the
+   * request is made. */
+  weval_req_arg_bytes = 4,
+  /* The same, but like `weval_assume_const_memory_transitive`. */
+  weval_req_arg_bytes_transitive = 5,
 } weval_req_arg_type;
 
+/* Tags on a specialized pointer argument, for the memory it points
+ * to. */
+typedef enum {
+  /* Loads through the pointer are constant, as if the generic function
+   * called `weval_assume_const_memory` on it. */
+  weval_req_arg_const_memory = 1 << 0,
+  /* Loads through the pointer, and through pointers loaded from it,
+   * are constant, as with `weval_assume_const_memory_transitive`. */
+  weval_req_arg_const_memory_transitive = 1 << 1,
+} weval_req_arg_tag;
+
+/* Or'd into the tags: the tags (and a bytes argument) are for memory
+ * `index` rather than the heap. Only the first 8 memories can be
+ * tagged. */
+#define WEVAL_REQ_ARG_MEMORY(index) ((uint16_t)(((index) + 1) << 8))
+
 struct weval_req_arg_t {
   uint32_t specialize;
-  uint32_t ty;
+  uint16_t ty;   /* weval_req_arg_type */
+  uint16_t tags; /* weval_req_arg_tag bits */
   union {
     uint32_t i32;
     uint64_t i64;
     float f32;
     double f64;
+    struct {
+      const void* data;
+      uint32_t len;
+    } bytes;
   } u;
 };
 
@@ -88,11 +115,18 @@ const void* weval_assume_const_memory(const void* p)
     WEVAL_WASM_IMPORT("assume.const.memory");
 const void* weval_assume_const_memory_transitive(const void* p)
     WEVAL_WASM_IMPORT("assume.const.memory.transitive");
+/* The same, for a pointer into memory `memory` rather than the heap. */
+const void* weval_assume_const_memory_in(const void* p, uint32_t memory)
+    WEVAL_WASM_IMPORT("assume.const.memory.in");
+const void* weval_assume_const_memory_transitive_in(const void* p,
+                                                    uint32_t memory)
+    WEVAL_WASM_IMPORT("assume.const.memory.transitive.in");
 void weval_push_context(uint32_t pc) WEVAL_WASM_IMPORT("push.context");
 void weval_pop_context() WEVAL_WASM_IMPORT("pop.context");
 void weval_update_context(uint32_t pc) WEVAL_WASM_IMPORT("update.context");
 void* weval_make_symbolic_ptr(void* p) WEVAL_WASM_IMPORT("make.symbolic.ptr");
 void weval_flush_to_mem() WEVAL_WASM_IMPORT("flush.to.mem");
+void weval_inline() WEVAL_WASM_IMPORT("inline");
 void weval_trace_line(uint32_t line_number) WEVAL_WASM_IMPORT("trace.line");
 void weval_abort_specialization(uint32_t line_number, uint32_t fatal)
     WEVAL_WASM_IMPORT("abort.specialization");
@@ -131,6 +165,23 @@ template <typename T>
 T* assume_const_memory_transitive(T* t) {
   return (T*)weval_assume_const_memory_transitive((void*)t);
 }
+template <typename T>
+const T* assume_const_memory(const T* t, uint32_t memory) {
+  return (const T*)weval_assume_const_memory_in((const void*)t, memory);
+}
+template <typename T>
+T* assume_const_memory(T* t, uint32_t memory) {
+  return (T*)weval_assume_const_memory_in((void*)t, memory);
+}
+template <typename T>
+const T* assume_const_memory_transitive(const T* t, uint32_t memory) {
+  return (const T*)weval_assume_const_memory_transitive_in((const void*)t,
+                                                           memory);
+}
+template <typename T>
+T* assume_const_memory_transitive(T* t, uint32_t memory) {
+  return (T*)weval_assume_const_memory_transitive_in((void*)t, memory);
+}
 
 static inline void push_context(uint32_t pc) { weval_push_context(pc); }
 
@@ -141,11 +192,18 @@ template <typename T>
 static T* make_symbolic_ptr(T* t) {
   return (T*)weval_make_symbolic_ptr((void*)t);
 }
+static inline void flush_to_mem() { weval_flush_to_mem(); }
+// The type parameter was never used; kept so that existing callers of
+// `flush_to_mem<T>()` still build.
 template <typename T>
-void flush_to_mem() {
-  weval_flush_to_mem();
+__attribute__((deprecated("use flush_to_mem()"))) void flush_to_mem() {
+  flush_to_mem();
 }
 
+// Marks the calling function to be inlined into its callers when
+// they are specialized.
+static inline void always_inline() { weval_inline(); }
+
 }  // namespace weval
 #endif  // __cplusplus
 
@@ -159,6 +217,15 @@ namespace weval {
 template <typename T>
 struct ArgSpec {};
 
+template <typename T>
+struct DefaultTags {
+  static constexpr uint16_t value = 0;
+};
+template <typename T>
+struct DefaultTags<const T*> {
+  static constexpr uint16_t value = weval_req_arg_const_memory;
+};
+
 template <typename T>
 struct RuntimeArg : ArgSpec<T> {};
 
@@ -170,7 +237,25 @@ RuntimeArg<T> Runtime() {
 template <typename T>
 struct Specialize : ArgSpec<T> {
   T value;
-  explicit Specialize(T value_) : value(value_) {}
+  // weval_req_arg_tag bits, with WEVAL_REQ_ARG_MEMORY for a pointer
+  // into another memory than the heap. A `const T*` is tagged as
+  // pointing to constant memory by default.
+  uint16_t tags;
+  explicit Specialize(T value_, uint16_t tags_ = DefaultTags<T>::value)
+      : value(value_), tags(tags_) {}
+};
+
+// A pointer argument to specialize on the contents of `len` elements
+// at `data`, which must not change once requested; the generic
+// function may read them as constant memory without calling
+// `assume_const_memory`.
+template <typename T>
+struct SpecializeBytes : ArgSpec<const T*> {
+  const T* data;
+  uint32_t len;
+  bool transitive;
+  SpecializeBytes(const T* data_, uint32_t len_, bool transitive_ = false)
+      : data(data_), len(len_), transitive(transitive_) {}
 };
 
 namespace impl {
@@ -255,6 +340,22 @@ template <typename T, typename... Rest>
 struct StoreArgs<Specialize<T>, Rest...> {
   void operator()(weval_req_arg_t* args, Specialize<T> arg0, Rest... rest) {
     StoreArg<T>()(args, arg0.value);
+    args[0].tags = arg0.tags;
+    StoreArgs<Rest...>()(args + 1, rest...);
+  }
+};
+
+template <typename T, typename... Rest>
+struct StoreArgs<SpecializeBytes<T>, Rest...> {
+  void operator()(weval_req_arg_t* args, SpecializeBytes<T> arg0,
+                  Rest... rest) {
+    static_assert(sizeof(const T*) == 4, "Only 32-bit Wasm supported");
+    args[0].specialize = 1;
+    args[0].ty = arg0.transitive ? weval_req_arg_bytes_transitive
+                                 : weval_req_arg_bytes;
+    args[0].tags = 0;
+    args[0].u.bytes.data = arg0.data;
+    args[0].u.bytes.len = arg0.len * sizeof(T);
     StoreArgs<Rest...>()(args + 1, rest...);
   }
 };
@@ -3,6 +3,10 @@
        local.get 0)
  (func (export "assume.const.memory.transitive") (param i32) (result i32)
        local.get 0)
+ (func (export "assume.const.memory.in") (param i32 i32) (result i32)
+       local.get 0)
+ (func (export "assume.const.memory.transitive.in") (param i32 i32) (result i32)
+       local.get 0)
  (func (export "push.context") (param i32))
  (func (export "pop.context"))
  (func (export "update.context") (param i32))
@@ -15,4 +19,8 @@
  (func (export "assert.const.memory") (param i32 i32))
  (func (export "specialize.value") (param i32 i32 i32) (result i32)
  local.get 0)
- (func (export "print") (param i32 i32 i32)))
+ (func (export "print") (param i32 i32 i32))
+ (func (export "make.symbolic.ptr") (param i32) (result i32)
+       local.get 0)
+ (func (export "flush.to.mem"))
+ (func (export "inline")))
@@ -0,0 +1,634 @@
+//! Interval and known-bits bounds on integer values.
+//!
+//! A `Bounds` describes an integer value that is not known at
+//! specialization time but is known to lie in an unsigned interval
+//! and to have some of its bits fixed. It is carried by
+//! `AbstractValue::Bounded`, and lets us decide compares (and hence
+//! branches) that do not depend on the exact value, e.g. a bounds
+//! check on a masked index or on the result of `specialize.value`.
+
+use crate::value::AbstractValue;
+use waffle::Operator;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bounds {
+    /// Width of the value in bits: 32 or 64.
+    pub bits: u32,
+    /// Inclusive unsigned lower bound.
+    pub lo: u64,
+    /// Inclusive unsigned upper bound.
+    pub hi: u64,
+    /// Bits known to be zero.
+    pub zeros: u64,
+    /// Bits known to be one.
+    pub ones: u64,
+}
+
+fn width_mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+impl Bounds {
+    pub fn full(bits: u32) -> Bounds {
+        Bounds {
+            bits,
+            lo: 0,
+            hi: width_mask(bits),
+            zeros: 0,
+            ones: 0,
+        }
+    }
+
+    pub fn constant(bits: u32, k: u64) -> Bounds {
+        Bounds::range(bits, k, k)
+    }
+
+    pub fn range(bits: u32, lo: u64, hi: u64) -> Bounds {
+        Bounds {
+            bits,
+            lo,
+            hi,
+            zeros: 0,
+            ones: 0,
+        }
+        .normalize()
+    }
+
+    fn known_bits(bits: u32, zeros: u64, ones: u64) -> Bounds {
+        Bounds {
+            zeros,
+            ones,
+            ..Bounds::full(bits)
+        }
+        .normalize()
+    }
+
+    fn mask(&self) -> u64 {
+        width_mask(self.bits)
+    }
+
+    /// Tighten the interval and the known bits against each other.
+    fn normalize(mut self) -> Bounds {
+        let mask = self.mask();
+        self.zeros &= mask;
+        self.ones &= mask;
+        self.hi = std::cmp::min(self.hi, mask);
+        if self.zeros & self.ones != 0 {
+            // Contradictory bits; can only come from unreachable
+            // code. Keep the interval only.
+            self.zeros = 0;
+            self.ones = 0;
+        }
+
+        // Set bits are a lower bound and clear bits an upper bound.
+        let lo = std::cmp::max(self.lo, self.ones);
+        let hi = std::cmp::min(self.hi, !self.zeros & mask);
+        if lo <= hi {
+            self.lo = lo;
+            self.hi = hi;
+        }
+
+        // All bits above the highest bit in which `lo` and `hi`
+        // differ are the same for every value in the interval.
+        let diff = self.lo ^ self.hi;
+        let common = if diff == 0 {
+            mask
+        } else {
+            mask & !(u64::MAX >> diff.leading_zeros())
+        };
+        self.zeros |= common & !self.lo;
+        self.ones |= common & self.lo;
+        self
+    }
+
+    pub fn as_constant(&self) -> Option<u64> {
+        if self.lo == self.hi {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, k: u64) -> bool {
+        self.lo <= k && k <= self.hi && k & self.zeros == 0 && k & self.ones == self.ones
+    }
+
+    /// Whether these bounds carry no information at all.
+    pub fn is_full(&self) -> bool {
+        self.lo == 0 && self.hi == self.mask() && self.zeros == 0 && self.ones == 0
+    }
+
+    /// The smallest bounds containing both `self` and `other`.
+    pub fn meet(&self, other: &Bounds) -> Bounds {
+        debug_assert_eq!(self.bits, other.bits);
+        Bounds {
+            bits: self.bits,
+            lo: std::cmp::min(self.lo, other.lo),
+            hi: std::cmp::max(self.hi, other.hi),
+            zeros: self.zeros & other.zeros,
+            ones: self.ones & other.ones,
+        }
+        .normalize()
+    }
+
+    /// Meet `next` into `self`, moving any bound that grew to its
+    /// extreme. Used where a value may keep growing around a loop,
+    /// so that the analysis converges. Known bits go with the bound
+    /// they would otherwise re-tighten.
+    pub fn widen(&self, next: &Bounds) -> Bounds {
+        let mut met = self.meet(next);
+        if met.lo < self.lo {
+            met.lo = 0;
+            met.ones = 0;
+        }
+        if met.hi > self.hi {
+            met.hi = self.mask();
+            met.zeros = 0;
+        }
+        met.normalize()
+    }
+
+    /// The values in both `self` and `other`, or `None` if there are
+    /// none.
+    pub fn intersect(&self, other: &Bounds) -> Option<Bounds> {
+        let lo = std::cmp::max(self.lo, other.lo);
+        let hi = std::cmp::min(self.hi, other.hi);
+        let zeros = self.zeros | other.zeros;
+        let ones = self.ones | other.ones;
+        if lo > hi || zeros & ones != 0 {
+            return None;
+        }
+        Some(
+            Bounds {
+                bits: self.bits,
+                lo,
+                hi,
+                zeros,
+                ones,
+            }
+            .normalize(),
+        )
+    }
+
+    /// `Some(true)` if all values are negative when read as signed,
+    /// `Some(false)` if none are.
+    fn sign(&self) -> Option<bool> {
+        let sign_bit = 1 << (self.bits - 1);
+        if self.hi < sign_bit {
+            Some(false)
+        } else if self.lo >= sign_bit {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Number of low bits known to be zero.
+    fn trailing_zeros(&self) -> u32 {
+        std::cmp::min((!self.zeros).trailing_zeros(), self.bits)
+    }
+
+    fn low_zeros(&self, n: u32) -> Bounds {
+        Bounds::known_bits(self.bits, width_mask(std::cmp::min(n, self.bits)), 0)
+    }
+
+    fn add(&self, other: &Bounds) -> Bounds {
+        match self.hi.checked_add(other.hi) {
+            Some(hi) if hi <= self.mask() => Bounds::range(self.bits, self.lo + other.lo, hi),
+            _ => self.low_zeros(std::cmp::min(self.trailing_zeros(), other.trailing_zeros())),
+        }
+    }
+
+    fn sub(&self, other: &Bounds) -> Bounds {
+        if self.lo >= other.hi {
+            Bounds::range(self.bits, self.lo - other.hi, self.hi - other.lo)
+        } else {
+            self.low_zeros(std::cmp::min(self.trailing_zeros(), other.trailing_zeros()))
+        }
+    }
+
+    fn mul(&self, other: &Bounds) -> Bounds {
+        match self.hi.checked_mul(other.hi) {
+            Some(hi) if hi <= self.mask() => Bounds::range(self.bits, self.lo * other.lo, hi),
+            _ => self.low_zeros(self.trailing_zeros() + other.trailing_zeros()),
+        }
+    }
+
+    fn and(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            bits: self.bits,
+            lo: 0,
+            hi: std::cmp::min(self.hi, other.hi),
+            zeros: self.zeros | other.zeros,
+            ones: self.ones & other.ones,
+        }
+        .normalize()
+    }
+
+    fn or(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            bits: self.bits,
+            lo: std::cmp::max(self.lo, other.lo),
+            hi: self.mask(),
+            zeros: self.zeros & other.zeros,
+            ones: self.ones | other.ones,
+        }
+        .normalize()
+    }
+
+    fn xor(&self, other: &Bounds) -> Bounds {
+        Bounds::known_bits(
+            self.bits,
+            (self.zeros & other.zeros) | (self.ones & other.ones),
+            (self.zeros & other.ones) | (self.ones & other.zeros),
+        )
+    }
+
+    fn shl(&self, shift: u64) -> Bounds {
+        let shift = (shift % self.bits as u64) as u32;
+        let mask = self.mask();
+        let zeros = (self.zeros << shift) | width_mask(shift);
+        let ones = self.ones << shift;
+        if self.hi <= mask >> shift {
+            Bounds {
+                bits: self.bits,
+                lo: self.lo << shift,
+                hi: self.hi << shift,
+                zeros,
+                ones,
+            }
+            .normalize()
+        } else {
+            Bounds::known_bits(self.bits, zeros, ones)
+        }
+    }
+
+    fn shr_u(&self, shift: u64) -> Bounds {
+        let shift = (shift % self.bits as u64) as u32;
+        let mask = self.mask();
+        Bounds {
+            bits: self.bits,
+            lo: self.lo >> shift,
+            hi: self.hi >> shift,
+            zeros: (self.zeros >> shift) | (mask & !(mask >> shift)),
+            ones: self.ones >> shift,
+        }
+        .normalize()
+    }
+
+    fn rem_u(&self, other: &Bounds) -> Bounds {
+        if self.hi < other.lo {
+            *self
+        } else if other.lo > 0 {
+            Bounds::range(self.bits, 0, std::cmp::min(self.hi, other.hi - 1))
+        } else {
+            Bounds::full(self.bits)
+        }
+    }
+
+    fn div_u(&self, other: &Bounds) -> Bounds {
+        match self.hi.checked_div(other.lo) {
+            Some(hi) => Bounds::range(self.bits, self.lo / other.hi, hi),
+            None => Bounds::full(self.bits),
+        }
+    }
+
+    fn eq(&self, other: &Bounds) -> Option<bool> {
+        if self.hi < other.lo
+            || other.hi < self.lo
+            || (self.ones & other.zeros) | (self.zeros & other.ones) != 0
+        {
+            Some(false)
+        } else {
+            match (self.as_constant(), other.as_constant()) {
+                (Some(a), Some(b)) if a == b => Some(true),
+                _ => None,
+            }
+        }
+    }
+
+    fn lt_u(&self, other: &Bounds) -> Option<bool> {
+        if self.hi < other.lo {
+            Some(true)
+        } else if self.lo >= other.hi {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn lt_s(&self, other: &Bounds) -> Option<bool> {
+        // Within one half of the range, signed and unsigned order
+        // agree.
+        match (self.sign()?, other.sign()?) {
+            (a, b) if a == b => self.lt_u(other),
+            (negative, _) => Some(negative),
+        }
+    }
+}
+
+/// The bounds of an operand of the given width.
+fn operand(av: &AbstractValue, bits: u32) -> Bounds {
+    av.int_bounds()
+        .filter(|b| b.bits == bits)
+        .unwrap_or(Bounds::full(bits))
+}
+
+fn boolean(result: Option<bool>) -> Bounds {
+    match result {
+        Some(b) => Bounds::constant(32, b as u64),
+        None => Bounds::range(32, 0, 1),
+    }
+}
+
+/// Bounds on the result of a unary operator, if we know anything
+/// about it.
+pub fn unary(op: &Operator, x: &AbstractValue) -> Option<Bounds> {
+    let result = match op {
+        Operator::I32Load8U { .. } | Operator::I64Load8U { .. } => {
+            let bits = if matches!(op, Operator::I32Load8U { .. }) {
+                32
+            } else {
+                64
+            };
+            return Some(Bounds::range(bits, 0, 0xff));
+        }
+        Operator::I32Load16U { .. } | Operator::I64Load16U { .. } => {
+            let bits = if matches!(op, Operator::I32Load16U { .. }) {
+                32
+            } else {
+                64
+            };
+            return Some(Bounds::range(bits, 0, 0xffff));
+        }
+        Operator::I64Load32U { .. } => return Some(Bounds::range(64, 0, 0xffff_ffff)),
+        Operator::I32Clz | Operator::I32Ctz | Operator::I32Popcnt => {
+            return Some(Bounds::range(32, 0, 32))
+        }
+        Operator::I64Clz | Operator::I64Ctz | Operator::I64Popcnt => {
+            return Some(Bounds::range(64, 0, 64))
+        }
+        _ => {
+            let bits = match op {
+                Operator::I32Eqz | Operator::I64ExtendI32U | Operator::I64ExtendI32S => 32,
+                Operator::I64Eqz | Operator::I32WrapI64 => 64,
+                _ => return None,
+            };
+            let a = operand(x, bits);
+            if a.is_full() {
+                return None;
+            }
+            match op {
+                Operator::I32Eqz | Operator::I64Eqz => boolean(a.eq(&Bounds::constant(a.bits, 0))),
+                Operator::I64ExtendI32U => Bounds {
+                    bits: 64,
+                    zeros: a.zeros | 0xffff_ffff_0000_0000,
+                    ..a
+                },
+                Operator::I64ExtendI32S if a.sign() == Some(false) => Bounds {
+                    bits: 64,
+                    zeros: a.zeros | 0xffff_ffff_0000_0000,
+                    ..a
+                },
+                Operator::I32WrapI64 if a.hi <= 0xffff_ffff => Bounds { bits: 32, ..a },
+                Operator::I32WrapI64 => Bounds::known_bits(32, a.zeros, a.ones),
+                _ => return None,
+            }
+        }
+    };
+    Some(result.normalize())
+}
+
+/// Bounds on the result of a binary operator, if we know anything
+/// about it.
+pub fn binary(op: &Operator, x: &AbstractValue, y: &AbstractValue) -> Option<Bounds> {
+    let bits = match op {
+        Operator::I32Eq
+        | Operator::I32Ne
+        | Operator::I32LtU
+        | Operator::I32LtS
+        | Operator::I32GtU
+        | Operator::I32GtS
+        | Operator::I32LeU
+        | Operator::I32LeS
+        | Operator::I32GeU
+        | Operator::I32GeS
+        | Operator::I32Add
+        | Operator::I32Sub
+        | Operator::I32Mul
+        | Operator::I32DivU
+        | Operator::I32RemU
+        | Operator::I32And
+        | Operator::I32Or
+        | Operator::I32Xor
+        | Operator::I32Shl
+        | Operator::I32ShrU
+        | Operator::I32ShrS => 32,
+        Operator::I64Eq
+        | Operator::I64Ne
+        | Operator::I64LtU
+        | Operator::I64LtS
+        | Operator::I64GtU
+        | Operator::I64GtS
+        | Operator::I64LeU
+        | Operator::I64LeS
+        | Operator::I64GeU
+        | Operator::I64GeS
+        | Operator::I64Add
+        | Operator::I64Sub
+        | Operator::I64Mul
+        | Operator::I64DivU
+        | Operator::I64RemU
+        | Operator::I64And
+        | Operator::I64Or
+        | Operator::I64Xor
+        | Operator::I64Shl
+        | Operator::I64ShrU
+        | Operator::I64ShrS => 64,
+        _ => return None,
+    };
+    let a = operand(x, bits);
+    let b = operand(y, bits);
+    if a.is_full() && b.is_full() {
+        return None;
+    }
+
+    let result = match op {
+        Operator::I32Eq | Operator::I64Eq => boolean(a.eq(&b)),
+        Operator::I32Ne | Operator::I64Ne => boolean(a.eq(&b).map(|r| !r)),
+        Operator::I32LtU | Operator::I64LtU => boolean(a.lt_u(&b)),
+        Operator::I32LtS | Operator::I64LtS => boolean(a.lt_s(&b)),
+        Operator::I32GtU | Operator::I64GtU => boolean(b.lt_u(&a)),
+        Operator::I32GtS | Operator::I64GtS => boolean(b.lt_s(&a)),
+        Operator::I32LeU | Operator::I64LeU => boolean(b.lt_u(&a).map(|r| !r)),
+        Operator::I32LeS | Operator::I64LeS => boolean(b.lt_s(&a).map(|r| !r)),
+        Operator::I32GeU | Operator::I64GeU => boolean(a.lt_u(&b).map(|r| !r)),
+        Operator::I32GeS | Operator::I64GeS => boolean(a.lt_s(&b).map(|r| !r)),
+        Operator::I32Add | Operator::I64Add => a.add(&b),
+        Operator::I32Sub | Operator::I64Sub => a.sub(&b),
+        Operator::I32Mul | Operator::I64Mul => a.mul(&b),
+        Operator::I32DivU | Operator::I64DivU => a.div_u(&b),
+        Operator::I32RemU | Operator::I64RemU => a.rem_u(&b),
+        Operator::I32And | Operator::I64And => a.and(&b),
+        Operator::I32Or | Operator::I64Or => a.or(&b),
+        Operator::I32Xor | Operator::I64Xor => a.xor(&b),
+        Operator::I32Shl | Operator::I64Shl => a.shl(b.as_constant()?),
+        Operator::I32ShrU | Operator::I64ShrU => a.shr_u(b.as_constant()?),
+        Operator::I32ShrS | Operator::I64ShrS if a.sign() == Some(false) => {
+            a.shr_u(b.as_constant()?)
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check the invariants that `normalize` maintains.
+    fn check(b: Bounds) -> Bounds {
+        let mask = b.mask();
+        assert!(b.lo <= b.hi && b.hi <= mask, "{:x?}", b);
+        assert_eq!(b.zeros & b.ones, 0, "{:x?}", b);
+        assert_eq!((b.zeros | b.ones) & !mask, 0, "{:x?}", b);
+        assert!(b.lo >= b.ones && b.hi <= !b.zeros & mask, "{:x?}", b);
+        b
+    }
+
+    #[test]
+    fn add_sub_wrap() {
+        let mask = width_mask(32);
+        let a = Bounds::range(32, 0xffff_fff0, 0xffff_ffff);
+        let k = Bounds::constant(32, 0x20);
+        let sum = check(a.add(&k));
+        for x in [0xffff_fff0u64, 0xffff_fff8, 0xffff_ffff] {
+            assert!(sum.contains((x + 0x20) & mask));
+        }
+
+        let a = Bounds::range(32, 0, 4);
+        let k = Bounds::constant(32, 8);
+        let diff = check(a.sub(&k));
+        for x in 0..=4u64 {
+            assert!(diff.contains(x.wrapping_sub(8) & mask));
+        }
+
+        // Without wrapping, the interval is exact.
+        assert_eq!(
+            Bounds::range(32, 4, 8).add(&Bounds::constant(32, 2)),
+            Bounds::range(32, 6, 10)
+        );
+        assert_eq!(
+            Bounds::range(32, 4, 8).sub(&Bounds::constant(32, 2)),
+            Bounds::range(32, 2, 6)
+        );
+
+        // Known low zeros survive wrapping.
+        let a = Bounds::known_bits(64, 0xf, 0);
+        let sum = check(a.add(&Bounds::constant(64, 0x10)));
+        assert_eq!(sum.zeros & 0xf, 0xf);
+    }
+
+    #[test]
+    fn shift_by_width() {
+        // Shift counts are taken modulo the width, as in Wasm.
+        assert_eq!(Bounds::constant(32, 3).shl(33), Bounds::constant(32, 6));
+        assert_eq!(Bounds::constant(32, 3).shl(32), Bounds::constant(32, 3));
+        assert_eq!(Bounds::constant(64, 3).shl(65), Bounds::constant(64, 6));
+        assert_eq!(Bounds::constant(32, 12).shr_u(34), Bounds::constant(32, 3));
+        assert_eq!(Bounds::range(64, 8, 16).shr_u(64), Bounds::range(64, 8, 16));
+
+        // Bits shifted out of the top wrap the interval; only the
+        // known low zeros are kept.
+        let shl = check(Bounds::range(32, 0, 0xffff).shl(20));
+        assert_eq!(shl.zeros & 0xf_ffff, 0xf_ffff);
+        for x in [0u64, 1, 0xfff, 0xffff] {
+            assert!(shl.contains((x << 20) & width_mask(32)));
+        }
+    }
+
+    #[test]
+    fn normalize_consistency() {
+        let b = check(Bounds::constant(32, 5));
+        assert_eq!((b.zeros, b.ones), (0xffff_fffa, 5));
+
+        // Known bits tighten the interval.
+        let b = check(Bounds::known_bits(32, 0xffff_ff00, 0x80));
+        assert_eq!((b.lo, b.hi), (0x80, 0xff));
+
+        // And the interval fixes the bits its values share.
+        let b = check(Bounds::range(32, 0x100, 0x1ff));
+        assert_eq!((b.zeros, b.ones), (0xffff_fe00, 0x100));
+
+        // Bits outside the width are dropped.
+        let b = check(Bounds::range(32, 0, u64::MAX));
+        assert!(b.is_full());
+
+        // Contradictory bits keep only the interval.
+        let b = check(
+            Bounds {
+                bits: 32,
+                lo: 0,
+                hi: 0xff,
+                zeros: 1,
+                ones: 1,
+            }
+            .normalize(),
+        );
+        assert_eq!((b.lo, b.hi), (0, 0xff));
+
+        for (a, b) in [
+            (Bounds::range(32, 3, 9), Bounds::known_bits(32, 0xf0, 0x1)),
+            (Bounds::range(64, 0, 100), Bounds::constant(64, 1 << 40)),
+        ] {
+            check(a.meet(&b));
+            check(a.and(&b));
+            check(a.or(&b));
+            check(a.xor(&b));
+            check(a.mul(&b));
+            if let Some(i) = a.intersect(&b) {
+                check(i);
+            }
+        }
+    }
+
+    #[test]
+    fn widen_converges() {
+        // A loop counter counting up from 0.
+        let one = Bounds::constant(32, 1);
+        let mut b = Bounds::constant(32, 0);
+        let mut steps = 0;
+        loop {
+            let next = b.widen(&b.add(&one));
+            if next == b {
+                break;
+            }
+            b = check(next);
+            steps += 1;
+            assert!(steps < 4, "widening did not converge: {:x?}", b);
+        }
+        assert!(b.contains(0) && b.contains(width_mask(32)));
+
+        // Counting down from 10.
+        let mut b = Bounds::constant(32, 10);
+        let mut steps = 0;
+        loop {
+            let next = b.widen(&b.meet(&b.sub(&one)));
+            if next == b {
+                break;
+            }
+            b = check(next);
+            steps += 1;
+            assert!(steps < 4, "widening did not converge: {:x?}", b);
+        }
+        assert_eq!(b.lo, 0);
+
+        // A bound that did not grow is kept.
+        let b = Bounds::range(32, 4, 8);
+        assert_eq!(b.widen(&Bounds::range(32, 4, 6)), b);
+    }
+}
@@ -0,0 +1,93 @@
+//! Diagnostics for errors in the program being specialized, and
+//! source positions from its debug info.
+
+use crate::directive::Directive;
+use waffle::entity::EntityRef;
+use waffle::{Block, Module, SourceLoc, Value};
+
+/// An error found while specializing a directive, such as a failed
+/// `assert.const32` or a runtime PC given to `update.context`, with
+/// the point in the original function where it occurred. Such an
+/// error cancels only the directive that hit it.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub directive: Directive,
+    /// Name of the original (generic) function.
+    pub func_name: String,
+    /// Block in the original function.
+    pub block: Block,
+    /// Instruction in the original function, if the error is at one.
+    pub inst: Option<Value>,
+    /// Source location of the instruction, if known.
+    pub loc: Option<SourceLoc>,
+    /// The source file and line of `loc`, from the input's DWARF.
+    pub pos: Option<SourcePos>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.func_name, self.block)?;
+        if let Some(inst) = self.inst {
+            write!(f, ": {}", inst)?;
+        }
+        match (&self.pos, self.loc) {
+            (Some(pos), _) => write!(f, " ({})", pos)?,
+            (None, Some(loc)) => write!(f, " ({})", loc)?,
+            (None, None) => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// A position in the source of the input module.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourcePos {
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl SourcePos {
+    /// Look up a source location in the module's debug info (parsed
+    /// from DWARF `.debug_line` by the frontend).
+    pub fn lookup(module: &Module, loc: SourceLoc) -> Option<SourcePos> {
+        if loc.is_invalid() || loc.index() >= module.debug.source_locs.len() {
+            return None;
+        }
+        let data = &module.debug.source_locs[loc];
+        Some(SourcePos {
+            file: module.debug.source_files[data.file].clone(),
+            line: data.line,
+            col: data.col,
+        })
+    }
+
+    /// The same position without the column, to group by line.
+    pub fn line_only(&self) -> SourcePos {
+        SourcePos {
+            col: 0,
+            ..self.clone()
+        }
+    }
+}
+
+impl std::fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        if self.col != 0 {
+            write!(f, ":{}", self.col)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe a source location for logging.
+pub fn describe_loc(module: &Module, loc: SourceLoc) -> String {
+    match SourcePos::lookup(module, loc) {
+        Some(pos) => pos.to_string(),
+        None => "unknown source location".to_owned(),
+    }
+}
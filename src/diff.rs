@@ -0,0 +1,105 @@
+//! Differential testing: run the original and the specialized module
+//! in waffle's interpreter, comparing their traces.
+
+use std::collections::VecDeque;
+
+struct TraceIter {
+    thread: std::thread::JoinHandle<()>,
+    channel: std::sync::mpsc::Receiver<(usize, Vec<waffle::ConstVal>)>,
+}
+
+impl TraceIter {
+    fn new(module: waffle::Module<'static>) -> anyhow::Result<TraceIter> {
+        let mut ctx = waffle::InterpContext::new(&module)?;
+        if let Some(start) = module.start_func {
+            if let Err(e) = ctx.call(&module, start, &[]).ok() {
+                anyhow::bail!("start function failed: {:?}", e);
+            }
+        }
+
+        let entry = if let Some(waffle::Export {
+            kind: waffle::ExportKind::Func(func),
+            ..
+        }) = module.exports.iter().find(|e| &e.name == "_start")
+        {
+            *func
+        } else {
+            anyhow::bail!("No _start entrypoint");
+        };
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
+        let thread = std::thread::spawn(move || {
+            let count = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+            let count_cloned = count.clone();
+            ctx.trace_handler = Some(Box::new(move |id, args| {
+                count_cloned.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                sender.send((id, args)).is_ok()
+            }));
+            let result = ctx.call(&module, entry, &[]);
+            if let Err(e) = result.ok() {
+                log::warn!(
+                    "Panic after {} steps: {:?}",
+                    count.load(std::sync::atomic::Ordering::Relaxed),
+                    e
+                );
+                let handler = ctx.trace_handler.unwrap();
+                handler(0, vec![]);
+            }
+        });
+
+        Ok(TraceIter {
+            thread,
+            channel: receiver,
+        })
+    }
+}
+
+impl Iterator for TraceIter {
+    type Item = (usize, Vec<waffle::ConstVal>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.channel.recv().ok()
+    }
+}
+
+/// Run both modules from `_start`, and fail on the first trace point
+/// at which they differ.
+pub fn run_diff(
+    orig_module: waffle::Module<'_>,
+    wevaled_module: waffle::Module<'_>,
+) -> anyhow::Result<()> {
+    let orig_text = format!("{}", orig_module.display());
+    let wevaled_text = format!("{}", wevaled_module.display());
+    let orig = TraceIter::new(orig_module.without_orig_bytes())?;
+    let wevaled = TraceIter::new(wevaled_module.without_orig_bytes())?;
+
+    let mut progress: u64 = 0;
+    let mut last_n = VecDeque::new();
+    for ((orig_id, orig_args), (wevaled_id, wevaled_args)) in orig.zip(wevaled) {
+        progress += 1;
+        if progress % 100000 == 0 {
+            log::info!("{} steps", progress);
+        }
+
+        last_n.push_back((orig_id, orig_args.clone()));
+        if last_n.len() > 10 {
+            last_n.pop_front();
+        }
+
+        if orig_id != wevaled_id || orig_args != wevaled_args {
+            log::error!("Original:\n{}\n", orig_text);
+            log::error!("wevaled:\n{}\n", wevaled_text);
+            log::error!("Recent tracepoints:");
+            for (id, args) in last_n {
+                log::error!("* {}, {:?}", id, args);
+            }
+            anyhow::bail!(
+                "Mismatch: orig ({}, {:?}), wevaled ({}, {:?})",
+                orig_id,
+                orig_args,
+                wevaled_id,
+                wevaled_args
+            );
+        }
+    }
+    Ok(())
+}
@@ -2,7 +2,8 @@
 
 use crate::image::Image;
 use crate::intrinsics::find_global_data_by_exported_func;
-use crate::value::{AbstractValue, ValueTags, WasmVal};
+use crate::value::{AbstractValue, ValueTags, WasmVal, MAX_TAGGED_MEMORIES};
+use waffle::entity::EntityRef;
 use waffle::{Func, Memory, Module};
 
 #[derive(Clone, Debug)]
@@ -12,8 +13,35 @@ pub struct Directive {
     /// Evaluate with the given parameter values fixed.
     pub const_params: Vec<AbstractValue>,
     /// Place the ID of the resulting specialized function at the
-    /// given address in memory.
-    pub func_index_out_addr: u32,
+    /// given address in memory. Directives synthesized for call-site
+    /// specialization have no address; their results are only called
+    /// directly.
+    pub func_index_out_addr: Option<u32>,
+    /// Export the resulting specialized function under this name.
+    pub export: Option<String>,
+    /// Overrides of the global specialization budget.
+    pub budget: Budget,
+}
+
+/// Per-directive overrides of the specialization budget. Unset
+/// limits fall back to the global options.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    pub max_blocks: Option<usize>,
+    pub max_values: Option<usize>,
+    pub max_contexts: Option<usize>,
+    /// Maximum size of the specialized function body, in bytes.
+    pub max_output_bytes: Option<usize>,
+    /// When the total code-size budget is exceeded, directives with
+    /// higher priority are kept first.
+    pub priority: i32,
+}
+
+impl Directive {
+    /// The function and arguments this directive specializes on.
+    pub fn key(&self) -> (Func, Vec<AbstractValue>) {
+        (self.func, self.const_params.clone())
+    }
 }
 
 pub fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>> {
@@ -28,10 +56,9 @@ pub fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>
         }
     };
 
-    let heap = match im.main_heap {
-        Some(heap) => heap,
-        None => return Ok(vec![]),
-    };
+    let heap = im
+        .main_heap
+        .ok_or_else(|| anyhow::anyhow!("no heap memory to read the request list from"))?;
 
     let mut head = im.read_u32(heap, pending_head_addr)?;
     let mut directives = vec![];
@@ -55,7 +82,7 @@ pub fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>
     Ok(directives)
 }
 
-fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Directive> {
+fn decode_weval_req(im: &mut Image, heap: Memory, head: u32) -> anyhow::Result<Directive> {
     let func_table_index = im.read_u32(heap, head + 8)?;
     let func = im.func_ptr(func_table_index)?;
     let mut arg_ptr = im.read_u32(heap, head + 12)?;
@@ -65,18 +92,35 @@ fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Direc
     let mut const_params = vec![];
     for _ in 0..nargs {
         let is_specialized = im.read_u32(heap, arg_ptr)?;
-        let ty = im.read_u32(heap, arg_ptr + 4)?;
-        let tags = ValueTags::default();
+        let ty = im.read_u16(heap, arg_ptr + 4)?;
+        let tag_bits = im.read_u16(heap, arg_ptr + 6)?;
         let value = if is_specialized != 0 {
+            let memory = arg_memory(im, heap, tag_bits)?;
+            let tags = decode_tags(memory, tag_bits)?;
+            if tags != ValueTags::default() && !matches!(ty, 0 | 4 | 5) {
+                anyhow::bail!("Tags on a non-pointer argument of type {}", ty);
+            }
             match ty {
                 0 => AbstractValue::Concrete(WasmVal::I32(im.read_u32(heap, arg_ptr + 8)?), tags),
                 1 => AbstractValue::Concrete(WasmVal::I64(im.read_u64(heap, arg_ptr + 8)?), tags),
                 2 => AbstractValue::Concrete(WasmVal::F32(im.read_u32(heap, arg_ptr + 8)?), tags),
                 3 => AbstractValue::Concrete(WasmVal::F64(im.read_u64(heap, arg_ptr + 8)?), tags),
+                4 | 5 => {
+                    // A buffer, already in the snapshot: pass the
+                    // caller's pointer, as pointing to constant memory.
+                    let ptr = im.read_u32(heap, arg_ptr + 8)?;
+                    let len = im.read_u32(heap, arg_ptr + 12)?;
+                    im.read_bytes(memory, ptr, len)?;
+                    let mut tags = tags | ValueTags::const_memory(memory);
+                    if ty == 5 {
+                        tags = tags | ValueTags::const_memory_transitive(memory);
+                    }
+                    AbstractValue::Concrete(WasmVal::I32(ptr), tags)
+                }
                 _ => anyhow::bail!("Invalid type: {}", ty),
             }
         } else {
-            AbstractValue::Runtime(None, tags)
+            AbstractValue::Runtime(None, ValueTags::default())
         };
         const_params.push(value);
         arg_ptr += 16;
@@ -85,6 +129,102 @@ fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Direc
     Ok(Directive {
         func,
         const_params,
-        func_index_out_addr,
+        func_index_out_addr: Some(func_index_out_addr),
+        export: None,
+        budget: Budget::default(),
     })
 }
+
+/// Tag bits of a request argument (`weval_req_arg_tag` in
+/// `weval.h`). They apply to the memory in the high byte, numbered
+/// from 1, or to the heap if that is 0.
+const ARG_TAG_CONST_MEMORY: u16 = 1 << 0;
+const ARG_TAG_CONST_MEMORY_TRANSITIVE: u16 = 1 << 1;
+const ARG_TAG_MEMORY_SHIFT: u16 = 8;
+
+/// The memory that a request argument's tags (and a buffer argument)
+/// refer to.
+fn arg_memory(im: &Image, heap: Memory, bits: u16) -> anyhow::Result<Memory> {
+    let index = (bits >> ARG_TAG_MEMORY_SHIFT) as usize;
+    if index == 0 {
+        return Ok(heap);
+    }
+    let memory = Memory::new(index - 1);
+    if memory.index() >= MAX_TAGGED_MEMORIES || !im.memories.contains_key(&memory) {
+        anyhow::bail!(
+            "Argument tags name memory {}, which can't be tagged",
+            index - 1
+        );
+    }
+    Ok(memory)
+}
+
+fn decode_tags(memory: Memory, bits: u16) -> anyhow::Result<ValueTags> {
+    let bits = bits & ((1 << ARG_TAG_MEMORY_SHIFT) - 1);
+    let known = ARG_TAG_CONST_MEMORY | ARG_TAG_CONST_MEMORY_TRANSITIVE;
+    if bits & !known != 0 {
+        anyhow::bail!("Unknown argument tags: 0x{:x}", bits & !known);
+    }
+    let mut tags = ValueTags::default();
+    if bits & (ARG_TAG_CONST_MEMORY | ARG_TAG_CONST_MEMORY_TRANSITIVE) != 0 {
+        tags = tags | ValueTags::const_memory(memory);
+    }
+    if bits & ARG_TAG_CONST_MEMORY_TRANSITIVE != 0 {
+        tags = tags | ValueTags::const_memory_transitive(memory);
+    }
+    Ok(tags)
+}
+
+/// Apply per-function budget overrides to directives. Each line of
+/// `contents` names a function, by name or index, followed by
+/// `key=value` settings for all directives on that function, e.g.:
+///
+/// ```text
+/// # function  settings
+/// interp      blocks=20000 values=200000 contexts=1000 bytes=65536 priority=10
+/// ```
+///
+/// `#` starts a comment.
+pub fn apply_budgets(
+    module: &Module,
+    directives: &mut [Directive],
+    contents: &str,
+) -> anyhow::Result<()> {
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let func = module
+            .funcs
+            .entries()
+            .find(|(_, decl)| decl.name() == name)
+            .map(|(func, _)| func)
+            .or_else(|| name.parse::<usize>().ok().map(Func::new))
+            .filter(|&func| func.index() < module.funcs.len())
+            .ok_or_else(|| anyhow::anyhow!("line {}: unknown function '{}'", i + 1, name))?;
+
+        let mut budget = Budget::default();
+        for setting in words {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("line {}: expected key=value", i + 1))?;
+            let invalid = || anyhow::anyhow!("line {}: invalid value for {}", i + 1, key);
+            match key {
+                "blocks" => budget.max_blocks = Some(value.parse().map_err(|_| invalid())?),
+                "values" => budget.max_values = Some(value.parse().map_err(|_| invalid())?),
+                "contexts" => budget.max_contexts = Some(value.parse().map_err(|_| invalid())?),
+                "bytes" => budget.max_output_bytes = Some(value.parse().map_err(|_| invalid())?),
+                "priority" => budget.priority = value.parse().map_err(|_| invalid())?,
+                _ => anyhow::bail!("line {}: unknown setting '{}'", i + 1, key),
+            }
+        }
+
+        for directive in directives.iter_mut().filter(|d| d.func == func) {
+            directive.budget = budget.clone();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,524 @@
+//! Directive files: specialization requests supplied alongside the
+//! module, rather than in its memory, for modules that were never
+//! instrumented with `weval_request` or can't be wizened. The format
+//! is TOML:
+//!
+//! ```toml
+//! [[directive]]
+//! # The function, by export name, name-section name or index.
+//! func = "interp"
+//! # Export the specialized function under this name, and/or write
+//! # its function-table index to this address in the heap.
+//! export = "interp_fib"
+//! out-addr = 0x1000
+//! args = [
+//!     { kind = "runtime" },
+//!     { kind = "const", type = "i32", value = 42 },
+//!     # A pointer to a copy of the file's contents, which weval places
+//!     # in the heap (once for equal contents). Loads through it are
+//!     # constant; with `transitive`, so are loads through pointers
+//!     # loaded from it.
+//!     { kind = "bytes", file = "fib.bc" },
+//!     # The same, with the contents given inline, as a string or an
+//!     # array of bytes.
+//!     { kind = "bytes", data = [0x01, 0x02, 0x03], transitive = true },
+//! ]
+//! ```
+//!
+//! Relative paths are relative to the directive file.
+//!
+//! Byte arguments are placed in pages added to the end of the heap,
+//! which is only safe if the program's allocator is already running
+//! (as in a Wizer snapshot) and so only grows memory from its end. An
+//! allocator that starts later may claim all memory up to the current
+//! size (wasi-libc's dlmalloc takes everything from `__heap_base` on)
+//! and hand out, and overwrite, the bytes. In such a module, byte
+//! arguments are only safe if the specialized code reads them all
+//! while specializing.
+
+use crate::directive::{Budget, Directive};
+use crate::image::Image;
+use crate::value::{AbstractValue, ValueTags, WasmVal};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use waffle::entity::EntityRef;
+use waffle::{ExportKind, Func, Module, Type};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectiveFile {
+    #[serde(default)]
+    directive: Vec<DirectiveSpec>,
+    /// Where relative paths are resolved from.
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct DirectiveSpec {
+    func: FuncSpec,
+    #[serde(default)]
+    args: Vec<ArgSpec>,
+    export: Option<String>,
+    out_addr: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum FuncSpec {
+    Index(u32),
+    Name(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+enum ArgSpec {
+    // A struct variant, so that unknown fields are denied here too.
+    Runtime {},
+    Const {
+        #[serde(rename = "type")]
+        ty: String,
+        value: toml::Value,
+    },
+    Bytes {
+        file: Option<PathBuf>,
+        data: Option<BytesSpec>,
+        #[serde(default)]
+        transitive: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum BytesSpec {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl DirectiveFile {
+    pub fn read(path: &Path) -> anyhow::Result<DirectiveFile> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+        let mut file = DirectiveFile::parse(&contents)
+            .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?;
+        file.base_dir = path.parent().map(Path::to_owned).unwrap_or_default();
+        Ok(file)
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<DirectiveFile> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// The directives, with functions looked up in `module`. Byte
+    /// arguments are placed in the heap.
+    pub fn resolve(&self, module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>> {
+        let mut exports = module
+            .exports
+            .iter()
+            .map(|export| export.name.clone())
+            .collect::<HashSet<_>>();
+        let mut directives = vec![];
+        for (i, spec) in self.directive.iter().enumerate() {
+            let directive = self
+                .resolve_directive(module, im, spec)
+                .map_err(|err| anyhow::anyhow!("directive {}: {}", i + 1, err))?;
+            if let Some(name) = &directive.export {
+                if !exports.insert(name.clone()) {
+                    anyhow::bail!("directive {}: duplicate export '{}'", i + 1, name);
+                }
+            }
+            directives.push(directive);
+        }
+        Ok(directives)
+    }
+
+    fn resolve_directive(
+        &self,
+        module: &Module,
+        im: &mut Image,
+        spec: &DirectiveSpec,
+    ) -> anyhow::Result<Directive> {
+        let func = find_func(module, &spec.func)?;
+        let params = &module.signatures[module.funcs[func].sig()].params;
+        if spec.args.len() != params.len() {
+            anyhow::bail!(
+                "{} takes {} arguments, but {} are given",
+                module.funcs[func].name(),
+                params.len(),
+                spec.args.len()
+            );
+        }
+        let const_params = spec
+            .args
+            .iter()
+            .zip(params.iter())
+            .enumerate()
+            .map(|(i, (arg, &ty))| {
+                self.resolve_arg(im, arg, ty)
+                    .map_err(|err| anyhow::anyhow!("argument {}: {}", i + 1, err))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Directive {
+            func,
+            const_params,
+            func_index_out_addr: spec.out_addr,
+            export: spec.export.clone(),
+            budget: Budget::default(),
+        })
+    }
+
+    fn resolve_arg(
+        &self,
+        im: &mut Image,
+        arg: &ArgSpec,
+        param_ty: Type,
+    ) -> anyhow::Result<AbstractValue> {
+        let tags = ValueTags::default();
+        Ok(match arg {
+            ArgSpec::Runtime {} => AbstractValue::Runtime(None, tags),
+            ArgSpec::Const { ty, value } => {
+                let value = const_value(ty, value)?;
+                if value.ty() != param_ty {
+                    anyhow::bail!("{} constant for {} parameter", ty, param_ty);
+                }
+                AbstractValue::Concrete(value, tags)
+            }
+            ArgSpec::Bytes { .. } if param_ty != Type::I32 => {
+                anyhow::bail!("pointer for {} parameter", param_ty)
+            }
+            ArgSpec::Bytes {
+                file,
+                data,
+                transitive,
+            } => {
+                let data = match (file, data) {
+                    (Some(file), None) => {
+                        let path = self.base_dir.join(file);
+                        std::fs::read(&path)
+                            .map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err))?
+                    }
+                    (None, Some(BytesSpec::Text(text))) => text.clone().into_bytes(),
+                    (None, Some(BytesSpec::Bytes(bytes))) => bytes.clone(),
+                    _ => anyhow::bail!("bytes need exactly one of `file` and `data`"),
+                };
+                let heap = im.main_heap()?;
+                let addr = im.alloc_const_bytes(heap, &data[..])?;
+                let mut tags = ValueTags::const_memory(heap);
+                if *transitive {
+                    tags = tags | ValueTags::const_memory_transitive(heap);
+                }
+                AbstractValue::Concrete(WasmVal::I32(addr), tags)
+            }
+        })
+    }
+}
+
+/// Find a function by index, or by export name or name-section name.
+/// A name that refers to different functions as an export and in the
+/// name section is ambiguous.
+fn find_func(module: &Module, spec: &FuncSpec) -> anyhow::Result<Func> {
+    let name = match spec {
+        FuncSpec::Index(index) if (*index as usize) < module.funcs.len() => {
+            return Ok(Func::new(*index as usize))
+        }
+        FuncSpec::Index(index) => anyhow::bail!("no function {}", index),
+        FuncSpec::Name(name) => name,
+    };
+    let exported = module.exports.iter().find_map(|export| match export.kind {
+        ExportKind::Func(func) if &export.name == name => Some(func),
+        _ => None,
+    });
+    let named = module
+        .funcs
+        .entries()
+        .find(|(_, decl)| decl.name() == name)
+        .map(|(func, _)| func);
+    match (exported, named) {
+        (Some(exported), Some(named)) if exported != named => anyhow::bail!(
+            "function '{}' is ambiguous: {} is exported as '{}', but {} has that name",
+            name,
+            exported,
+            name,
+            named
+        ),
+        (Some(func), _) | (_, Some(func)) => Ok(func),
+        (None, None) => anyhow::bail!("no function exported or named as '{}'", name),
+    }
+}
+
+fn const_value(ty: &str, value: &toml::Value) -> anyhow::Result<WasmVal> {
+    let invalid = || anyhow::anyhow!("invalid {} constant: {}", ty, value);
+    Ok(match (ty, value) {
+        ("i32", toml::Value::Integer(k)) => {
+            // Accept both signed and unsigned 32-bit values.
+            if *k < i32::MIN as i64 || *k > u32::MAX as i64 {
+                return Err(invalid());
+            }
+            WasmVal::I32(*k as u32)
+        }
+        ("i64", toml::Value::Integer(k)) => WasmVal::I64(*k as u64),
+        ("f32", toml::Value::Float(k)) => WasmVal::F32((*k as f32).to_bits()),
+        ("f32", toml::Value::Integer(k)) => WasmVal::F32((*k as f32).to_bits()),
+        ("f64", toml::Value::Float(k)) => WasmVal::F64(k.to_bits()),
+        ("f64", toml::Value::Integer(k)) => WasmVal::F64((*k as f64).to_bits()),
+        ("i32" | "i64" | "f32" | "f64", _) => return Err(invalid()),
+        _ => anyhow::bail!("unknown type '{}'", ty),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{build_image, RootNames};
+    use wasm_encoder::{
+        CodeSection, ExportSection, Function, FunctionSection, Instruction, MemorySection,
+        MemoryType, NameMap, NameSection, TypeSection, ValType,
+    };
+
+    /// A module exporting its memory, `f(i32)` as function 0 and
+    /// `g(f64)` as function 1, with function 2 named `g` in the name
+    /// section.
+    fn module_bytes() -> Vec<u8> {
+        let mut module = wasm_encoder::Module::new();
+        let mut types = TypeSection::new();
+        types.function([ValType::I32], []);
+        types.function([ValType::F64], []);
+        module.section(&types);
+        let mut funcs = FunctionSection::new();
+        funcs.function(0).function(1).function(0);
+        module.section(&funcs);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        module.section(&memories);
+        let mut exports = ExportSection::new();
+        exports
+            .export("f", wasm_encoder::ExportKind::Func, 0)
+            .export("g", wasm_encoder::ExportKind::Func, 1)
+            .export("memory", wasm_encoder::ExportKind::Memory, 0);
+        module.section(&exports);
+        let mut code = CodeSection::new();
+        for _ in 0..3 {
+            let mut body = Function::new([]);
+            body.instruction(&Instruction::End);
+            code.function(&body);
+        }
+        module.section(&code);
+        let mut names = NameSection::new();
+        let mut func_names = NameMap::new();
+        func_names.append(2, "g");
+        names.functions(&func_names);
+        module.section(&names);
+        module.finish()
+    }
+
+    fn resolve(contents: &str) -> anyhow::Result<Vec<Directive>> {
+        let bytes = module_bytes();
+        let module = Module::from_wasm_bytes(&bytes[..], &Default::default())?;
+        let names = RootNames {
+            stack_pointer: None,
+            heap: None,
+            table: None,
+        };
+        let mut im = build_image(&module, &names)?;
+        DirectiveFile::parse(contents)?.resolve(&module, &mut im)
+    }
+
+    fn resolve_err(contents: &str) -> String {
+        resolve(contents).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parse_example() {
+        let file = DirectiveFile::parse(
+            r#"
+            [[directive]]
+            func = "interp"
+            export = "interp_fib"
+            out-addr = 0x1000
+            args = [
+                { kind = "runtime" },
+                { kind = "const", type = "i32", value = 42 },
+                { kind = "bytes", file = "fib.bc" },
+                { kind = "bytes", data = [0x01, 0x02, 0x03], transitive = true },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.directive.len(), 1);
+        let spec = &file.directive[0];
+        assert!(matches!(&spec.func, FuncSpec::Name(name) if name == "interp"));
+        assert_eq!(spec.export.as_deref(), Some("interp_fib"));
+        assert_eq!(spec.out_addr, Some(0x1000));
+        assert!(matches!(spec.args[0], ArgSpec::Runtime {}));
+        assert!(matches!(&spec.args[1], ArgSpec::Const { ty, .. } if ty == "i32"));
+        assert!(matches!(
+            &spec.args[2],
+            ArgSpec::Bytes { file: Some(file), data: None, transitive: false }
+                if file == Path::new("fib.bc")
+        ));
+        assert!(matches!(
+            &spec.args[3],
+            ArgSpec::Bytes { file: None, data: Some(BytesSpec::Bytes(bytes)), transitive: true }
+                if bytes == &[1, 2, 3]
+        ));
+
+        assert!(DirectiveFile::parse("").unwrap().directive.is_empty());
+    }
+
+    #[test]
+    fn parse_unknown_fields() {
+        for contents in [
+            "funcs = 1",
+            "[[directive]]\nfunc = 0\nexports = \"x\"",
+            "[[directive]]\nfunc = 0\nargs = [{ kind = \"runtime\", value = 1 }]",
+            "[[directive]]\nfunc = 0\nargs = [{ kind = \"bytes\", path = \"x\" }]",
+            "[[directive]]\nfunc = 0\nargs = [{ kind = \"pointer\" }]",
+            "[[directive]]\nexport = \"x\"",
+        ] {
+            assert!(DirectiveFile::parse(contents).is_err(), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn const_values() {
+        let int = toml::Value::Integer;
+        let i32_value = |k| const_value("i32", &int(k)).ok();
+        assert_eq!(i32_value(-1), Some(WasmVal::I32(0xffff_ffff)));
+        assert_eq!(i32_value(0xffff_ffff), Some(WasmVal::I32(0xffff_ffff)));
+        assert_eq!(i32_value(i32::MIN as i64), Some(WasmVal::I32(0x8000_0000)));
+        assert_eq!(i32_value(i32::MIN as i64 - 1), None);
+        assert_eq!(i32_value(u32::MAX as i64 + 1), None);
+        assert_eq!(
+            const_value("i64", &int(-1)).unwrap(),
+            WasmVal::I64(u64::MAX)
+        );
+
+        // Integer literals are accepted for floats.
+        assert_eq!(
+            const_value("f32", &int(3)).unwrap(),
+            WasmVal::F32(3.0f32.to_bits())
+        );
+        assert_eq!(
+            const_value("f64", &int(-2)).unwrap(),
+            WasmVal::F64((-2.0f64).to_bits())
+        );
+        assert_eq!(
+            const_value("f64", &toml::Value::Float(0.5)).unwrap(),
+            WasmVal::F64(0.5f64.to_bits())
+        );
+
+        let err = |ty, value| const_value(ty, &value).unwrap_err().to_string();
+        assert!(err("i32", toml::Value::Float(1.0)).starts_with("invalid i32 constant"));
+        assert!(err("i64", toml::Value::String("1".into())).starts_with("invalid i64 constant"));
+        assert!(err("f32", toml::Value::Boolean(true)).starts_with("invalid f32 constant"));
+        assert_eq!(err("v128", int(0)), "unknown type 'v128'");
+    }
+
+    #[test]
+    fn resolve_directives() {
+        let directives = resolve(
+            r#"
+            [[directive]]
+            func = "f"
+            export = "f1"
+            args = [{ kind = "const", type = "i32", value = -1 }]
+
+            [[directive]]
+            func = 1
+            args = [{ kind = "runtime" }]
+
+            [[directive]]
+            func = "f"
+            args = [{ kind = "bytes", data = "abc", transitive = true }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(directives.len(), 3);
+        assert_eq!(directives[0].func, Func::new(0));
+        assert_eq!(directives[0].export.as_deref(), Some("f1"));
+        assert!(matches!(
+            directives[0].const_params[0],
+            AbstractValue::Concrete(WasmVal::I32(0xffff_ffff), _)
+        ));
+        assert_eq!(directives[1].func, Func::new(1));
+        assert!(matches!(
+            directives[1].const_params[0],
+            AbstractValue::Runtime(..)
+        ));
+        assert!(matches!(
+            directives[2].const_params[0],
+            AbstractValue::Concrete(WasmVal::I32(_), tags) if tags != ValueTags::default()
+        ));
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let cases = [
+            ("func = 7\nargs = []", "directive 1: no function 7"),
+            (
+                "func = \"h\"\nargs = []",
+                "directive 1: no function exported or named as 'h'",
+            ),
+            (
+                "func = \"g\"\nargs = []",
+                "directive 1: function 'g' is ambiguous",
+            ),
+            ("func = 0\nargs = []", "takes 1 arguments, but 0 are given"),
+            (
+                "func = 0\nargs = [{ kind = \"const\", type = \"f64\", value = 1 }]",
+                "argument 1: f64 constant for i32 parameter",
+            ),
+            (
+                "func = 0\nargs = [{ kind = \"const\", type = \"i32\", value = 1.5 }]",
+                "argument 1: invalid i32 constant",
+            ),
+            (
+                "func = 1\nargs = [{ kind = \"bytes\", data = \"x\" }]",
+                "argument 1: pointer for f64 parameter",
+            ),
+            (
+                "func = 0\nargs = [{ kind = \"bytes\" }]",
+                "bytes need exactly one of `file` and `data`",
+            ),
+            (
+                "func = 0\nargs = [{ kind = \"bytes\", file = \"x\", data = \"x\" }]",
+                "bytes need exactly one of `file` and `data`",
+            ),
+            (
+                "func = 0\nargs = [{ kind = \"bytes\", file = \"does-not-exist.bin\" }]",
+                "does-not-exist.bin: ",
+            ),
+            (
+                "func = 0\nexport = \"g\"\nargs = [{ kind = \"runtime\" }]",
+                "directive 1: duplicate export 'g'",
+            ),
+        ];
+        for (spec, expected) in cases {
+            let err = resolve_err(&format!("[[directive]]\n{}", spec));
+            assert!(err.contains(expected), "{}: {}", spec, err);
+        }
+
+        // Exports must be unique across directives, too.
+        let err = resolve_err(
+            r#"
+            [[directive]]
+            func = 0
+            export = "x"
+            args = [{ kind = "runtime" }]
+
+            [[directive]]
+            func = 0
+            export = "x"
+            args = [{ kind = "runtime" }]
+            "#,
+        );
+        assert_eq!(err, "directive 2: duplicate export 'x'");
+    }
+}
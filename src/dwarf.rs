@@ -0,0 +1,665 @@
+//! Carrying DWARF debug info over to the output module.
+//!
+//! DWARF for Wasm describes code by offset into the code section. The
+//! bodies of the input's functions reach the output unchanged, or (in
+//! the intrinsic-stripping filter) rewritten without changing their
+//! length where possible, but they may move; so we rewrite the
+//! `.debug_*` sections, mapping each address in a function body to
+//! the same offset in the function's new body, shifted past any
+//! instructions that the filter had to make longer (`Growth`).
+//!
+//! Functions that we compile from IR are new code: specialized
+//! functions, and (with `--run-diff`) instrumented generic ones. Each
+//! gets a subprogram, in a compile unit of its own, and a line table
+//! with a row at the start of each block's code, for the source line
+//! of the block's first instruction (see `compile_with_lines`). The
+//! debug info of a generic function whose body was recompiled is
+//! tombstoned (see `map_address`) in favor of this.
+
+use crate::diagnostic::SourcePos;
+use fxhash::FxHashMap as HashMap;
+use fxhash::FxHashSet as HashSet;
+use gimli::write::{
+    self, Address, AttributeValue, EndianVec, LineProgram, LineString, RangeList, Sections,
+};
+use gimli::{Encoding, EndianSlice, Format, LineEncoding, LittleEndian, SectionId};
+use std::ops::Range;
+use waffle::entity::EntityRef;
+use waffle::pool::ListRef;
+use waffle::{Block, FunctionBody, Global, Module, Operator, SourceLoc, Type, ValueDef};
+use wasm_encoder::Encode;
+use wasmparser::{Parser, Payload, TypeRef};
+
+/// The address we give to code that no longer exists.
+const TOMBSTONE: u64 = 0xffff_ffff;
+
+/// The global index of the marker for block 0 (see
+/// `compile_with_lines`). No module has this many globals.
+const BLOCK_MARKER_BASE: usize = 0x4000_0000;
+
+/// Line-table rows for a compiled function body: source positions by
+/// offset in the body.
+pub type Lines = Vec<(u64, SourcePos)>;
+
+/// A function compiled from IR, e.g. by specialization.
+#[derive(Clone, Debug)]
+pub struct NewFunc {
+    /// Index of the function in the output.
+    pub func: u32,
+    /// Index of the generic function it came from, in the input.
+    pub generic: u32,
+    pub name: String,
+    /// Line-table rows, by offset in the compiled body. Without any,
+    /// the whole body gets the first line of the generic function.
+    pub lines: Lines,
+}
+
+/// A place where the filter made a function body longer: in the body
+/// with index `body` (among the bodies in the code section), code
+/// from offset `at` in the input body on is `by` bytes further along.
+#[derive(Clone, Copy, Debug)]
+pub struct Growth {
+    pub body: usize,
+    pub at: u64,
+    pub by: u64,
+}
+
+/// The function bodies of a module, as ranges of offsets in its code
+/// section.
+struct CodeLayout {
+    num_imported_funcs: u32,
+    bodies: Vec<Range<u64>>,
+}
+
+impl CodeLayout {
+    fn parse(module: &[u8]) -> anyhow::Result<CodeLayout> {
+        let mut num_imported_funcs = 0;
+        let mut code_start = 0;
+        let mut bodies = vec![];
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        if let TypeRef::Func(_) = import?.ty {
+                            num_imported_funcs += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionStart { range, .. } => {
+                    code_start = range.start;
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let range = body.range();
+                    bodies.push((range.start - code_start) as u64..(range.end - code_start) as u64);
+                }
+                _ => {}
+            }
+        }
+        Ok(CodeLayout {
+            num_imported_funcs,
+            bodies,
+        })
+    }
+
+    fn body(&self, func: u32) -> Option<Range<u64>> {
+        let index = func.checked_sub(self.num_imported_funcs)?;
+        self.bodies.get(index as usize).cloned()
+    }
+}
+
+/// Whether a custom section holds DWARF debug info.
+pub fn is_debug_section(name: &str) -> bool {
+    name.starts_with(".debug_")
+}
+
+/// The `.debug_*` custom sections of a module.
+fn debug_sections(module: &[u8]) -> anyhow::Result<Vec<(&str, &[u8])>> {
+    let mut sections = vec![];
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CustomSection(reader) = payload? {
+            if is_debug_section(reader.name()) {
+                sections.push((reader.name(), reader.data()));
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// Append a custom section to an encoded module.
+pub fn append_custom_section(module: &mut Vec<u8>, name: &str, data: &[u8]) {
+    use wasm_encoder::Encode;
+    module.push(wasm_encoder::SectionId::Custom as u8);
+    wasm_encoder::CustomSection { name, data }.encode(module);
+}
+
+/// Carry the DWARF sections of `input` over to `output`. The function
+/// bodies of `output` must correspond, in order, to those of `input`
+/// (grown in the places in `growth`), followed by any new functions
+/// among `new_funcs`.
+///
+/// Debug info that we cannot rewrite is dropped with a warning:
+/// without it, the output is still correct, just harder to debug.
+pub fn carry_over(
+    input: &[u8],
+    mut output: Vec<u8>,
+    new_funcs: &[NewFunc],
+    growth: &[Growth],
+) -> anyhow::Result<Vec<u8>> {
+    let sections = debug_sections(input)?;
+    if !sections.iter().any(|&(name, _)| name == ".debug_info") {
+        return Ok(output);
+    }
+    let from = CodeLayout::parse(input)?;
+    let to = CodeLayout::parse(&output[..])?;
+    let mut moves = Moves::default();
+    for g in growth {
+        moves.growth.entry(g.body).or_default().push((g.at, g.by));
+    }
+    for new_func in new_funcs {
+        if let Some(index) = new_func.func.checked_sub(from.num_imported_funcs) {
+            moves.recompiled.insert(index as usize);
+        }
+    }
+    match rewrite(&sections[..], &from, &to, &moves, new_funcs) {
+        Ok(sections) => {
+            for (name, data) in sections {
+                append_custom_section(&mut output, name, &data[..]);
+            }
+        }
+        Err(err) => log::warn!("Dropping DWARF debug info: {:#}", err),
+    }
+    Ok(output)
+}
+
+/// How the input's function bodies changed on the way to the output.
+#[derive(Default)]
+struct Moves {
+    /// `Growth` by body index: offsets in the input body, and how many
+    /// bytes further code from there on moved.
+    growth: HashMap<usize, Vec<(u64, u64)>>,
+    /// Bodies (by index) replaced by code compiled from IR, which is
+    /// described in a unit of its own.
+    recompiled: HashSet<usize>,
+}
+
+/// Map a code offset in `from` to the same offset in the
+/// corresponding body in `to`, past any growth before it. Offsets
+/// outside any body, except 0 (the usual base address of a compile
+/// unit), belong to code that no longer exists. So does the old code
+/// of a recompiled body, or one whose length changed otherwise: we
+/// cannot tell where its instructions went, and line-table rows are
+/// carried over as offsets from the start of their sequence, so any
+/// address we gave it would make its lines wrong rather than only
+/// coarse.
+fn map_address(from: &CodeLayout, to: &CodeLayout, moves: &Moves, addr: u64) -> u64 {
+    if addr == 0 {
+        return 0;
+    }
+    let index = match from.bodies.partition_point(|body| body.start <= addr) {
+        0 => return TOMBSTONE,
+        index => index - 1,
+    };
+    let (old, new) = match to.bodies.get(index) {
+        Some(new) if !moves.recompiled.contains(&index) => (&from.bodies[index], new),
+        _ => return TOMBSTONE,
+    };
+    let growth = moves.growth.get(&index).map(|g| &g[..]).unwrap_or(&[]);
+    let grown = |offset: u64| -> u64 {
+        growth
+            .iter()
+            .filter(|&&(at, _)| at <= offset)
+            .map(|&(_, by)| by)
+            .sum()
+    };
+    let old_len = old.end - old.start;
+    if addr > old.end || old_len + grown(old_len) != new.end - new.start {
+        return TOMBSTONE;
+    }
+    let offset = addr - old.start;
+    new.start + offset + grown(offset)
+}
+
+fn rewrite(
+    sections: &[(&str, &[u8])],
+    from: &CodeLayout,
+    to: &CodeLayout,
+    moves: &Moves,
+    new_funcs: &[NewFunc],
+) -> anyhow::Result<Vec<(&'static str, Vec<u8>)>> {
+    let dwarf = gimli::read::Dwarf::load(|id: SectionId| -> gimli::Result<_> {
+        let data = sections
+            .iter()
+            .find(|&&(name, _)| name == id.name())
+            .map(|&(_, data)| data)
+            .unwrap_or(&[]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    })?;
+
+    let mut out = write::Dwarf::from(&dwarf, &|addr| {
+        Some(Address::Constant(map_address(from, to, moves, addr)))
+    })?;
+    if !moves.growth.is_empty() {
+        remap_lines(&mut out, &dwarf, &|addr| map_address(from, to, moves, addr))?;
+    }
+    if !new_funcs.is_empty() {
+        add_new_funcs(&mut out, &dwarf, from, to, new_funcs)?;
+    }
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    out.write(&mut sections)?;
+    let mut result = vec![];
+    sections.for_each(|id, data| -> gimli::write::Result<()> {
+        if !data.slice().is_empty() {
+            result.push((id.name(), data.slice().to_vec()));
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+/// Rebuild the line programs of `out` from those of `dwarf`, mapping
+/// the address of each row with `map`. `write::LineProgram::from`
+/// maps only the start of each sequence and keeps the offsets of its
+/// rows, which are wrong past any growth in a body.
+///
+/// Files are added in the same order as there, so the `FileId`s that
+/// the converted DIEs refer to stay valid.
+fn remap_lines(
+    out: &mut write::Dwarf,
+    dwarf: &gimli::read::Dwarf<EndianSlice<LittleEndian>>,
+    map: &dyn Fn(u64) -> u64,
+) -> anyhow::Result<()> {
+    let mut units = dwarf.units();
+    let mut index = 0;
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let id = out.units.id(index);
+        index += 1;
+        let from = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+        let header = from.header();
+        let comp_dir = match header.directory(0) {
+            Some(dir) => line_string(out, dwarf, dir)?,
+            None => LineString::String(vec![]),
+        };
+        let (comp_name, comp_info) = match header.file(0) {
+            Some(file) => (
+                line_string(out, dwarf, file.path_name())?,
+                Some(file_info(file)),
+            ),
+            None => (LineString::String(vec![]), None),
+        };
+        let mut program = LineProgram::new(
+            header.encoding(),
+            header.line_encoding(),
+            comp_dir,
+            comp_name.clone(),
+            comp_info,
+        );
+        program.file_has_timestamp = header.file_has_timestamp();
+        program.file_has_size = header.file_has_size();
+        program.file_has_md5 = header.file_has_md5();
+        let mut dirs = vec![program.default_directory()];
+        for dir in header.include_directories() {
+            let dir = line_string(out, dwarf, *dir)?;
+            dirs.push(program.add_directory(dir));
+        }
+        // File 0 is the compilation file in DWARF 5, and invalid before.
+        let skip = if header.version() <= 4 { 0 } else { 1 };
+        let mut files = vec![None];
+        for file in header.file_names().iter().skip(skip) {
+            let name = line_string(out, dwarf, file.path_name())?;
+            let dir = match dirs.get(file.directory_index() as usize) {
+                Some(&dir) => dir,
+                None => anyhow::bail!("Invalid directory index in line program"),
+            };
+            files.push(Some(program.add_file(name, dir, Some(file_info(file)))));
+        }
+
+        let mut rows = from.rows();
+        let mut sequence: Option<(u64, u64)> = None;
+        let mut last = 0;
+        while let Some((header, row)) = rows.next_row()? {
+            let addr = row.address();
+            let (old_base, new_base) = *sequence.get_or_insert_with(|| {
+                let base = map(addr);
+                program.begin_sequence(Some(Address::Constant(base)));
+                last = 0;
+                (addr, base)
+            });
+            let offset = if new_base == TOMBSTONE {
+                addr - old_base
+            } else {
+                map(addr).saturating_sub(new_base)
+            };
+            last = offset.max(last);
+            if row.end_sequence() {
+                program.end_sequence(last);
+                sequence = None;
+                continue;
+            }
+            let file = match files.get(row.file_index() as usize) {
+                Some(&Some(file)) => file,
+                Some(None) if header.version() > 4 => {
+                    let dir = program.default_directory();
+                    let file = program.add_file(comp_name.clone(), dir, comp_info);
+                    files[0] = Some(file);
+                    file
+                }
+                _ => anyhow::bail!("Invalid file index in line program"),
+            };
+            let out_row = program.row();
+            out_row.address_offset = last;
+            out_row.op_index = row.op_index();
+            out_row.file = file;
+            out_row.line = row.line().map_or(0, |line| line.get());
+            out_row.column = match row.column() {
+                gimli::ColumnType::LeftEdge => 0,
+                gimli::ColumnType::Column(column) => column.get(),
+            };
+            out_row.discriminator = row.discriminator();
+            out_row.is_statement = row.is_stmt();
+            out_row.basic_block = row.basic_block();
+            out_row.prologue_end = row.prologue_end();
+            out_row.epilogue_begin = row.epilogue_begin();
+            out_row.isa = row.isa();
+            program.generate_row();
+        }
+        out.units.get_mut(id).line_program = program;
+    }
+    Ok(())
+}
+
+fn line_string(
+    out: &mut write::Dwarf,
+    dwarf: &gimli::read::Dwarf<EndianSlice<LittleEndian>>,
+    attr: gimli::read::AttributeValue<EndianSlice<LittleEndian>>,
+) -> anyhow::Result<LineString> {
+    Ok(match attr {
+        gimli::read::AttributeValue::String(s) => LineString::String(s.to_vec()),
+        gimli::read::AttributeValue::DebugStrRef(offset) => {
+            let s = dwarf.debug_str.get_str(offset)?;
+            LineString::StringRef(out.strings.add(s.to_vec()))
+        }
+        gimli::read::AttributeValue::DebugLineStrRef(offset) => {
+            let s = dwarf.debug_line_str.get_str(offset)?;
+            LineString::LineStringRef(out.line_strings.add(s.to_vec()))
+        }
+        _ => anyhow::bail!("Unsupported string form in line program"),
+    })
+}
+
+fn file_info(file: &gimli::read::FileEntry<EndianSlice<LittleEndian>>) -> write::FileInfo {
+    write::FileInfo {
+        timestamp: file.timestamp(),
+        size: file.size(),
+        md5: *file.md5(),
+    }
+}
+
+/// Describe `new_funcs` in a new compile unit.
+fn add_new_funcs(
+    out: &mut write::Dwarf,
+    dwarf: &gimli::read::Dwarf<EndianSlice<LittleEndian>>,
+    from: &CodeLayout,
+    to: &CodeLayout,
+    new_funcs: &[NewFunc],
+) -> anyhow::Result<()> {
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut program = LineProgram::new(
+        encoding,
+        LineEncoding::default(),
+        LineString::String(vec![]),
+        LineString::String(b"<specialized>".to_vec()),
+        None,
+    );
+    let dir = program.default_directory();
+    let generic_bodies = new_funcs
+        .iter()
+        .filter(|new_func| new_func.lines.is_empty())
+        .filter_map(|new_func| from.body(new_func.generic))
+        .collect::<Vec<_>>();
+    let lines = first_lines(dwarf, &generic_bodies[..])?;
+    let mut funcs = vec![];
+    for new_func in new_funcs {
+        let body = match to.body(new_func.func) {
+            Some(body) => body,
+            None => continue,
+        };
+        program.begin_sequence(Some(Address::Constant(body.start)));
+        if new_func.lines.is_empty() {
+            let line = from
+                .body(new_func.generic)
+                .and_then(|generic| lines.get(&generic.start));
+            if let Some((path, line)) = line {
+                let file =
+                    program.add_file(LineString::String(path.clone().into_bytes()), dir, None);
+                program.row().file = file;
+                program.row().line = *line;
+                program.generate_row();
+            }
+        }
+        for (offset, pos) in &new_func.lines {
+            let file =
+                program.add_file(LineString::String(pos.file.clone().into_bytes()), dir, None);
+            program.row().address_offset = *offset;
+            program.row().file = file;
+            program.row().line = pos.line as u64;
+            program.row().column = pos.col as u64;
+            program.generate_row();
+        }
+        program.end_sequence(body.end - body.start);
+        funcs.push((new_func, body));
+    }
+
+    let unit = out.units.add(write::Unit::new(encoding, program));
+    let unit = out.units.get_mut(unit);
+    let ranges = unit.ranges.add(RangeList(
+        funcs
+            .iter()
+            .map(|(_, body)| write::Range::StartLength {
+                begin: Address::Constant(body.start),
+                length: body.end - body.start,
+            })
+            .collect(),
+    ));
+    let root = unit.root();
+    let root_die = unit.get_mut(root);
+    root_die.set(
+        gimli::DW_AT_name,
+        AttributeValue::String(b"<specialized>".to_vec()),
+    );
+    root_die.set(
+        gimli::DW_AT_producer,
+        AttributeValue::String(b"weval".to_vec()),
+    );
+    root_die.set(
+        gimli::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+    root_die.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+    for (new_func, body) in funcs {
+        let die = unit.add(root, gimli::DW_TAG_subprogram);
+        let die = unit.get_mut(die);
+        die.set(
+            gimli::DW_AT_name,
+            AttributeValue::String(new_func.name.clone().into_bytes()),
+        );
+        die.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(body.start)),
+        );
+        die.set(
+            gimli::DW_AT_high_pc,
+            AttributeValue::Udata(body.end - body.start),
+        );
+    }
+    Ok(())
+}
+
+/// The source file and line of the lowest-addressed line-table row
+/// in each of `bodies`, by body start.
+fn first_lines(
+    dwarf: &gimli::read::Dwarf<EndianSlice<LittleEndian>>,
+    bodies: &[Range<u64>],
+) -> anyhow::Result<HashMap<u64, (String, u64)>> {
+    let mut bodies = bodies.to_vec();
+    bodies.sort_by_key(|body| body.start);
+    let mut first: HashMap<u64, (u64, String, u64)> = HashMap::default();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            let addr = row.address();
+            if row.end_sequence() {
+                continue;
+            }
+            let index = bodies.partition_point(|body| body.start <= addr);
+            let body = match index.checked_sub(1).map(|i| &bodies[i]) {
+                Some(body) if body.contains(&addr) => body,
+                _ => continue,
+            };
+            if first
+                .get(&body.start)
+                .is_some_and(|&(first, ..)| first <= addr)
+            {
+                continue;
+            }
+            let (line, file) = match (row.line(), row.file(header)) {
+                (Some(line), Some(file)) => (line.get(), file),
+                _ => continue,
+            };
+            let name = dwarf.attr_string(&unit, file.path_name())?;
+            let mut path = String::new();
+            if let Some(dir) = file.directory(header) {
+                let dir = dwarf.attr_string(&unit, dir)?;
+                if !dir.is_empty() && !name.starts_with(b"/") {
+                    path.push_str(&dir.to_string_lossy());
+                    path.push('/');
+                }
+            }
+            path.push_str(&name.to_string_lossy());
+            first.insert(body.start, (addr, path, line));
+        }
+    }
+    Ok(first
+        .into_iter()
+        .map(|(start, (_, path, line))| (start, (path, line)))
+        .collect())
+}
+
+/// Compile `body`, and describe the result with a line-table row at
+/// the start of each block's code: the source position of the block's
+/// first instruction that has one, or else of `fallback(block)`. Rows
+/// are by offset in the compiled body (its locals, then its code).
+///
+/// waffle's backend does not tell where it places blocks. So we
+/// compile a copy of the body with a marker at the start of each
+/// block, a `global.set` (of a constant) to a global that does not
+/// exist whose index names the block, and then cut the markers out of
+/// the code. They have no result, so they need no locals.
+pub fn compile_with_lines(
+    module: &Module,
+    body: &FunctionBody,
+    fallback: impl Fn(Block) -> Option<SourceLoc>,
+) -> anyhow::Result<(wasm_encoder::Function, Lines)> {
+    let mut positions = HashMap::default();
+    for (block, def) in body.blocks.entries() {
+        let loc = def
+            .insts
+            .iter()
+            .map(|&inst| body.source_locs[inst])
+            .find(|loc| !loc.is_invalid())
+            .or_else(|| fallback(block));
+        if let Some(pos) = loc.and_then(|loc| SourcePos::lookup(module, loc)) {
+            positions.insert(block, pos);
+        }
+    }
+    if positions.is_empty() {
+        return Ok((body.compile()?, vec![]));
+    }
+
+    let mut marked = body.clone();
+    let ty = marked.single_type_list(Type::I32);
+    for &block in positions.keys() {
+        let zero = marked.add_value(ValueDef::Operator(
+            Operator::I32Const { value: 0 },
+            ListRef::default(),
+            ty,
+        ));
+        let args = marked.arg_pool.single(zero);
+        let marker = marked.add_value(ValueDef::Operator(
+            Operator::GlobalSet {
+                global_index: Global::new(BLOCK_MARKER_BASE + block.index()),
+            },
+            args,
+            ListRef::default(),
+        ));
+        marked.blocks[block].insts.splice(0..0, [zero, marker]);
+    }
+    let mut encoded = vec![];
+    marked.compile()?.encode(&mut encoded);
+    let mut reader = wasmparser::BinaryReader::new(&encoded[..]);
+    reader.read_var_u32()?;
+    let data = &encoded[reader.current_position()..];
+    let parsed = wasmparser::FunctionBody::new(0, data);
+
+    let mut locals = vec![];
+    let mut locals_reader = parsed.get_locals_reader()?;
+    for _ in 0..locals_reader.get_count() {
+        let (count, ty) = locals_reader.read()?;
+        locals.push((count, wasm_encoder::ValType::from(Type::from(ty))));
+    }
+
+    let mut ops = parsed.get_operators_reader()?;
+    let code_start = ops.original_position();
+    let mut code = vec![];
+    let mut lines: Lines = vec![];
+    let mut last = code_start;
+    let mut prev = None;
+    while !ops.eof() {
+        let (op, offset) = ops.read_with_offset()?;
+        let block = match op {
+            wasmparser::Operator::GlobalSet { global_index }
+                if global_index as usize >= BLOCK_MARKER_BASE =>
+            {
+                Block::new(global_index as usize - BLOCK_MARKER_BASE)
+            }
+            op => {
+                prev = Some((op, offset));
+                continue;
+            }
+        };
+        let start = match prev.take() {
+            Some((wasmparser::Operator::I32Const { value: 0 }, start)) => start,
+            _ => anyhow::bail!("no constant before the marker for {}", block),
+        };
+        code.extend_from_slice(&data[last..start]);
+        last = ops.original_position();
+        // Blocks without code of their own share an offset with the
+        // next one.
+        let offset = (code_start + code.len()) as u64;
+        let pos = &positions[&block];
+        match lines.last_mut() {
+            Some((last_offset, last_pos)) if *last_offset == offset => *last_pos = pos.clone(),
+            Some((_, last_pos)) if last_pos == pos => {}
+            _ => lines.push((offset, pos.clone())),
+        }
+    }
+    code.extend_from_slice(&data[last..]);
+
+    let mut func = wasm_encoder::Function::new(locals);
+    func.raw(code);
+    Ok((func, lines))
+}
@@ -1,23 +1,28 @@
 //! Partial evaluation.
 
-use crate::directive::Directive;
+use crate::bounds::{self, Bounds};
+use crate::diagnostic::{describe_loc, Diagnostic, SourcePos};
+use crate::directive::{Budget, Directive};
+use crate::dwarf;
 use crate::image::Image;
+use crate::inline;
 use crate::intrinsics::Intrinsics;
 use crate::state::*;
 use crate::stats::SpecializationStats;
-use crate::value::{AbstractValue, ValueTags, WasmVal};
-use crate::Options;
+use crate::value::{AbstractValue, ValueTags, WasmVal, MAX_TAGGED_MEMORIES};
+use crate::{Options, Progress, ProgressFn};
 use fxhash::FxHashMap as HashMap;
 use fxhash::FxHashSet as HashSet;
 use rayon::prelude::*;
 use std::collections::{hash_map::Entry as HashEntry, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use waffle::cfg::CFGInfo;
 use waffle::entity::EntityRef;
 use waffle::pool::ListRef;
 use waffle::{
-    entity::PerEntity, Block, BlockDef, BlockTarget, FuncDecl, FunctionBody, Module, Operator,
-    Signature, SourceLoc, Table, Terminator, Type, Value, ValueDef,
+    entity::PerEntity, Block, BlockDef, BlockTarget, Func, FuncDecl, FunctionBody, Memory, Module,
+    Operator, Signature, SourceLoc, Table, Terminator, Type, Value, ValueDef,
 };
 
 struct Evaluator<'a> {
@@ -25,6 +30,8 @@ struct Evaluator<'a> {
     module: &'a Module<'a>,
     /// Original function body.
     generic: &'a FunctionBody,
+    /// The directive being specialized.
+    directive: &'a Directive,
     /// Intrinsic function indices.
     intrinsics: &'a Intrinsics,
     /// Memory image.
@@ -49,6 +56,25 @@ struct Evaluator<'a> {
     value_dep_blocks: HashMap<(Context, Value), BTreeSet<Block>>,
     /// Map of (ctx, block, idx) to blockparams for specialization-register values.
     reg_map: HashMap<(Context, Block, u64), Value>,
+    /// Map of (ctx, block, addr) to blockparams for memory-overlay values.
+    mem_map: HashMap<(Context, Block, MemAddr), Value>,
+    /// Whether to record calls for call-site specialization.
+    specialize_calls: bool,
+    /// Calls with some constant args, and the directive that would
+    /// specialize the callee on them.
+    call_sites: Vec<(Value, Directive)>,
+    /// Limits on the size of the specialization.
+    limits: Limits,
+    /// The line number and fatality of an `abort.specialization`
+    /// point that specialization reached, if any, and where it is in
+    /// the generic function (block and call).
+    aborted: Option<(u32, bool, Block, Value)>,
+    /// Number of times the bounds of each specialized value have
+    /// changed, to decide when to widen them.
+    bounds_updates: HashMap<Value, u32>,
+    /// Number of times the bounds in each block's entry state have
+    /// changed, likewise.
+    entry_bounds_updates: HashMap<Block, u32>,
     /// Queue of blocks to (re)compute. List of (block_in_generic,
     /// ctx, block_in_func).
     queue: VecDeque<(Block, Context, Block)>,
@@ -60,6 +86,22 @@ pub struct PartialEvalResult<'a> {
     pub orig_module: Option<Module<'a>>,
     pub module: Module<'a>,
     pub stats: Vec<SpecializationStats>,
+    /// Directives that could not be specialized.
+    pub failures: Vec<Failure>,
+    /// Specialized functions added to `module`, with the generic
+    /// function each came from and the line-table rows of its
+    /// compiled body (see `dwarf::compile_with_lines`).
+    pub specialized: Vec<(Func, Func, dwarf::Lines)>,
+}
+
+/// A directive that could not be specialized, and why.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub directive: Directive,
+    pub reason: String,
+    /// Where in the original function specialization failed, for
+    /// errors in the program itself.
+    pub diagnostic: Option<Diagnostic>,
 }
 
 /// Partially evaluates according to the given directives. Returns
@@ -69,38 +111,195 @@ pub fn partially_evaluate<'a>(
     im: &mut Image,
     directives: &[Directive],
     opts: &Options,
-    mut progress: Option<indicatif::ProgressBar>,
+    progress: Option<&ProgressFn>,
 ) -> anyhow::Result<PartialEvalResult<'a>> {
     let intrinsics = Intrinsics::find(&module);
     log::trace!("intrinsics: {:?}", intrinsics);
     let mut mem_updates = HashMap::default();
 
-    // Sort directives by out-address, and remove duplicates.
-    let mut directives = directives.to_vec();
-    directives.sort_by_key(|d| d.func_index_out_addr);
-    directives.dedup_by_key(|d| d.func_index_out_addr);
+    // Requests for one out-address must agree: keep the first and
+    // drop the others with a warning.
+    let mut requests: Vec<Directive> = vec![];
+    let mut by_out_addr: HashMap<u32, usize> = HashMap::default();
+    for directive in directives {
+        if let Some(addr) = directive.func_index_out_addr {
+            if let Some(&i) = by_out_addr.get(&addr) {
+                if requests[i].key() != directive.key() {
+                    log::warn!(
+                        "Conflicting requests for out-address 0x{:x}: keeping {:?}, dropping {:?}",
+                        addr,
+                        requests[i],
+                        directive
+                    );
+                    continue;
+                }
+            }
+            by_out_addr.entry(addr).or_insert(requests.len());
+        }
+        requests.push(directive.clone());
+    }
 
-    let mut funcs = HashMap::default();
-    for directive in &directives {
-        if !funcs.contains_key(&directive.func) {
-            let mut f = module.clone_and_expand_body(directive.func)?;
+    // Specialize once per function and arguments; requests that share
+    // them share the result.
+    let mut requested = HashSet::default();
+    let directives = requests
+        .iter()
+        .filter(|directive| requested.insert(directive.key()))
+        .cloned()
+        .collect::<Vec<_>>();
 
-            let stats = Mutex::new(SpecializationStats::new(directive.func, &f));
+    let mut funcs = HashMap::default();
 
-            split_blocks_at_specialization_points(&mut f, &intrinsics);
+    // With `--keep-going`, a directive that fails with an error is
+    // recorded as a failure (like one that runs out of budget) and
+    // the rest of the batch continues. Directives we create for call
+    // sites ourselves always fail softly: their calls keep going to
+    // the generic callee.
+    let keep_going = opts.keep_going || opts.fallback_to_generic;
+    let keep_going_for =
+        |directive: &Directive| keep_going || !requested.contains(&directive.key());
+    let mut failures = vec![];
+
+    let total = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let report = || {
+        if let Some(progress) = progress {
+            progress(Progress {
+                done: done.load(Ordering::Relaxed),
+                total: total.load(Ordering::Relaxed),
+            });
+        }
+    };
 
-            f.recompute_edges();
-            let cfg = CFGInfo::new(&f);
-            let cut_blocks = find_cut_blocks(&f, &cfg, &intrinsics);
+    // Specialize in rounds: the first round handles the requested
+    // directives, and each later round handles the callee
+    // specializations requested by call sites in the previous round's
+    // output, up to the configured depth.
+    let mut results: Vec<(Directive, Option<SpecializedFunc>)> = vec![];
+    let mut memo: HashMap<(Func, Vec<AbstractValue>), usize> = HashMap::default();
+    let mut round = directives;
+    let mut depth = 0;
+    let mut unprepared = HashSet::default();
+    while !round.is_empty() {
+        for directive in &round {
+            if !funcs.contains_key(&directive.func) && !unprepared.contains(&directive.func) {
+                match prepare_generic(&mut module, directive.func, &intrinsics, opts) {
+                    Ok(f) => {
+                        funcs.insert(directive.func, f);
+                    }
+                    Err(err) => {
+                        record_failure(&mut failures, keep_going_for(directive), directive, err)?;
+                        unprepared.insert(directive.func);
+                    }
+                }
+            }
+        }
+        round.retain(|directive| funcs.contains_key(&directive.func));
+
+        total.fetch_add(round.len(), Ordering::Relaxed);
+        report();
+
+        let round_results = round
+            .par_iter()
+            .map(|directive| {
+                let (generic, cfg, stats) = funcs.get(&directive.func).unwrap();
+                let result = partially_evaluate_func(
+                    &module,
+                    generic,
+                    cfg,
+                    im,
+                    &intrinsics,
+                    opts,
+                    directive,
+                )?;
+
+                done.fetch_add(1, Ordering::Relaxed);
+                report();
+                if let Ok(result) = result.as_ref() {
+                    stats.lock().unwrap().add_specialization(
+                        &result.body,
+                        &result.block_rev_map,
+                        &result.contexts,
+                    );
+                }
+                Ok(result)
+            })
+            .collect::<Vec<anyhow::Result<_>>>();
+
+        let first = results.len();
+        for (directive, result) in round.into_iter().zip(round_results) {
+            let result = match result {
+                Ok(Ok(result)) => Some(result),
+                Ok(Err(cancelled)) => {
+                    log::info!(
+                        "Failed to weval for directive {:?}: {}",
+                        directive,
+                        cancelled
+                    );
+                    failures.push(Failure {
+                        directive: directive.clone(),
+                        reason: cancelled.to_string(),
+                        diagnostic: None,
+                    });
+                    None
+                }
+                Err(err) => {
+                    record_failure(&mut failures, keep_going_for(&directive), &directive, err)?;
+                    None
+                }
+            };
+            memo.entry(directive.key()).or_insert(results.len());
+            results.push((directive, result));
+        }
 
-            f.convert_to_max_ssa(Some(cut_blocks));
+        let mut next = vec![];
+        if depth < opts.call_specialization_depth {
+            for (_, result) in &results[first..] {
+                for (_, callee) in result.iter().flat_map(|r| r.call_sites.iter()) {
+                    if !memo.contains_key(&callee.key()) {
+                        next.push(callee.clone());
+                    }
+                }
+            }
+        }
+        next.sort_by_key(|d| d.key());
+        next.dedup_by(|a, b| a.key() == b.key());
+        log::debug!(
+            "call-site specialization round {}: {} new directives",
+            depth + 1,
+            next.len()
+        );
+        round = next;
+        depth += 1;
+    }
 
-            if opts.run_diff {
-                waffle::passes::trace::run(&mut f);
-                module.replace_body(directive.func, f.clone());
+    // Keep within the total code-size budget: take specializations
+    // in priority order (then in order of request) until one does not
+    // fit, and skip it and the rest.
+    if let Some(max_total) = opts.max_total_bytes {
+        let mut order = (0..results.len())
+            .filter(|&i| results[i].1.is_some())
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(results[i].0.budget.priority));
+        let mut total = 0;
+        let mut exceeded = false;
+        for i in order {
+            let size = results[i].1.as_ref().unwrap().size.unwrap();
+            if !exceeded && total + size <= max_total {
+                total += size;
+                continue;
             }
-            funcs.insert(directive.func, (f, cfg, stats));
+            exceeded = true;
+            let (directive, result) = &mut results[i];
+            log::info!("Skipping directive {:?}: over total budget", directive);
+            *result = None;
+            failures.push(Failure {
+                directive: directive.clone(),
+                reason: "exceeded the total code-size budget".to_owned(),
+                diagnostic: None,
+            });
         }
+        log::info!("Total specialized code size: {} bytes", total);
     }
 
     let mut orig_module = if opts.run_diff {
@@ -109,88 +308,185 @@ pub fn partially_evaluate<'a>(
         None
     };
 
-    if let Some(p) = progress.as_mut() {
-        p.set_length(directives.len() as u64);
-    }
-
-    let directives = directives
+    // Allocate function indices, then redirect specialized call
+    // sites to their specialized callees.
+    let func_indices = results
         .iter()
-        .zip(std::iter::from_fn(move || {
-            Some(progress.as_ref().map(|bar| bar.clone()))
-        }))
+        .map(|(_, result)| result.as_ref().map(|_| module.funcs.push(FuncDecl::None)))
         .collect::<Vec<_>>();
-    let bodies = directives
-        .par_iter()
-        .flat_map(|(directive, progress)| {
-            let (generic, cfg, stats) = funcs.get(&directive.func).unwrap();
-            let result =
-                match partially_evaluate_func(&module, generic, cfg, im, &intrinsics, directive) {
-                    Ok(result) => result,
-                    Err(e) => return Some(Err(e)),
-                };
+    for (_, result) in &mut results {
+        if let Some(result) = result.as_mut() {
+            for (call, callee) in &result.call_sites {
+                let specialized = memo.get(&callee.key()).and_then(|&i| func_indices[i]);
+                if let (
+                    Some(specialized),
+                    ValueDef::Operator(Operator::Call { function_index }, ..),
+                ) = (specialized, &mut result.body.values[*call])
+                {
+                    log::debug!(
+                        "redirecting call {} from {} to {}",
+                        call,
+                        function_index,
+                        specialized
+                    );
+                    *function_index = specialized;
+                }
+            }
+        }
+    }
+
+    let decls = results
+        .par_iter_mut()
+        .map(|(directive, result)| {
+            result
+                .take()
+                .map(|result| {
+                    Ok(if opts.run_diff {
+                        (FuncDecl::Body(result.sig, result.name, result.body), vec![])
+                    } else {
+                        // Blocks whose instructions were all folded
+                        // away take the line of their generic block.
+                        let (generic, ..) = &funcs[&directive.func];
+                        let (compiled, lines) =
+                            dwarf::compile_with_lines(&module, &result.body, |block| {
+                                let (_, orig) = result.block_rev_map[block];
+                                if orig.is_invalid() {
+                                    return None;
+                                }
+                                generic.blocks[orig]
+                                    .insts
+                                    .iter()
+                                    .map(|&inst| generic.source_locs[inst])
+                                    .find(|loc| !loc.is_invalid())
+                            })?;
+                        (FuncDecl::Compiled(result.sig, result.name, compiled), lines)
+                    })
+                })
+                .transpose()
+        })
+        .collect::<Vec<anyhow::Result<_>>>();
 
-            if let Some(p) = progress {
-                p.inc(1);
+    let mut specialized = vec![];
+    for (((directive, _), decl), &func) in results.iter().zip(decls).zip(&func_indices) {
+        let func = match func {
+            Some(func) => func,
+            None => continue,
+        };
+        // Add function to module. If it failed to compile, its index
+        // (which call sites may already refer to) gets a copy of the
+        // generic function instead.
+        let lines = match decl {
+            Ok(decl) => {
+                let (decl, lines) = decl.unwrap();
+                module.funcs[func] = decl;
+                lines
             }
-            if let Some((body, sig, name, block_rev_map, contexts)) = result {
-                stats
-                    .lock()
-                    .unwrap()
-                    .add_specialization(&body, &block_rev_map, &contexts);
-                let decl = if opts.run_diff {
-                    FuncDecl::Body(sig, name, body)
-                } else {
-                    let body = match body.compile() {
-                        Ok(body) => body,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    FuncDecl::Compiled(sig, name, body)
-                };
-                Some(Ok((directive, decl)))
-            } else {
-                log::info!("Failed to weval for directive {:?}", directive);
-                None
+            Err(err) => {
+                record_failure(&mut failures, keep_going_for(directive), directive, err)?;
+                module.funcs[func] = module.funcs[directive.func].clone();
+                vec![]
             }
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
-    for (directive, decl) in bodies {
-        // Add function to module.
-        let func = module.funcs.push(decl);
-        // Append to table.
-        let func_table = &mut module.tables[Table::from(0)];
-        let table_idx = {
-            let func_table_elts = func_table.func_elements.as_mut().unwrap();
-            let table_idx = func_table_elts.len();
-            func_table_elts.push(func);
-            table_idx
-        } as u32;
-        if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
-            func_table.max = Some(table_idx + 1);
-        }
-        log::info!("New func index {} -> table index {}", func, table_idx);
-        log::info!(" -> writing to 0x{:x}", directive.func_index_out_addr);
-
-        // If we're doing differential testing, append to *original
-        // module*'s function table too, but with the generic function
-        // index.
-        if opts.run_diff {
-            let orig_func_table = &mut orig_module.as_mut().unwrap().tables[Table::from(0)];
-            let orig_func_table_elts = orig_func_table.func_elements.as_mut().unwrap();
-            assert_eq!(table_idx, orig_func_table_elts.len() as u32);
-            orig_func_table_elts.push(directive.func);
-            if orig_func_table.max.is_some() && table_idx >= orig_func_table.max.unwrap() {
-                orig_func_table.max = Some(table_idx + 1);
+        };
+        specialized.push((func, directive.func, lines));
+    }
+
+    // Export each request's specialized function, and write its table
+    // index to the request's out-address. Requests that share a
+    // specialization share its table index.
+    let mut table_indices: Vec<Option<u32>> = vec![None; results.len()];
+    let mut fallbacks = vec![];
+    for directive in &requests {
+        let i = match memo.get(&directive.key()) {
+            Some(&i) if func_indices[i].is_some() => i,
+            _ => {
+                fallbacks.push(directive);
+                continue;
             }
+        };
+        let func = func_indices[i].unwrap();
+        if let Some(name) = &directive.export {
+            export_func(&mut module, name, func);
         }
+        let out_addr = match directive.func_index_out_addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        let table_idx = match table_indices[i] {
+            Some(table_idx) => table_idx,
+            None => {
+                // Append to table.
+                let table = im.main_table()?;
+                let func_table = &mut module.tables[table];
+                let table_idx = {
+                    let func_table_elts = func_table.func_elements.as_mut().unwrap();
+                    let table_idx = func_table_elts.len();
+                    func_table_elts.push(func);
+                    table_idx
+                } as u32;
+                if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
+                    func_table.max = Some(table_idx + 1);
+                }
+                log::info!("New func index {} -> table index {}", func, table_idx);
+
+                // If we're doing differential testing, append to
+                // *original module*'s function table too, but with the
+                // generic function index.
+                if opts.run_diff {
+                    let orig_func_table = &mut orig_module.as_mut().unwrap().tables[table];
+                    let orig_func_table_elts = orig_func_table.func_elements.as_mut().unwrap();
+                    assert_eq!(table_idx, orig_func_table_elts.len() as u32);
+                    orig_func_table_elts.push(directive.func);
+                    if orig_func_table.max.is_some() && table_idx >= orig_func_table.max.unwrap() {
+                        orig_func_table.max = Some(table_idx + 1);
+                    }
+                }
+                table_indices[i] = Some(table_idx);
+                table_idx
+            }
+        };
+        log::info!(" -> writing to 0x{:x}", out_addr);
 
         // Update memory image.
-        mem_updates.insert(directive.func_index_out_addr, table_idx);
+        mem_updates.insert(out_addr, table_idx);
+    }
+
+    // Point the out-addresses of failed requests at the generic
+    // function, if requested, so the program need not handle a
+    // missing specialization.
+    if opts.fallback_to_generic {
+        for directive in fallbacks {
+            if let Some(name) = &directive.export {
+                export_func(&mut module, name, directive.func);
+            }
+            let out_addr = match directive.func_index_out_addr {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let table = im.main_table()?;
+            let table_idx = generic_table_index(&mut module, table, directive.func);
+            if opts.run_diff {
+                let orig_table_idx =
+                    generic_table_index(orig_module.as_mut().unwrap(), table, directive.func);
+                assert_eq!(table_idx, orig_table_idx);
+            }
+            log::info!(
+                "Generic func {} -> table index {} as fallback",
+                directive.func,
+                table_idx
+            );
+            log::info!(" -> writing to 0x{:x}", out_addr);
+            mem_updates.insert(out_addr, table_idx);
+        }
     }
 
-    // Update memory.
-    let heap = im.main_heap()?;
-    for (addr, value) in mem_updates {
-        im.write_u32(heap, addr, value)?;
+    // Update memory. Modules specialized only by exported directives
+    // need not have a heap.
+    if !mem_updates.is_empty() {
+        let heap = im.main_heap()?;
+        for (addr, value) in mem_updates {
+            im.write_u32(heap, addr, value)?;
+        }
     }
 
     let mut stats = funcs
@@ -198,30 +494,124 @@ pub fn partially_evaluate<'a>(
         .map(|(_, (_, _, stats))| stats.into_inner().unwrap())
         .collect::<Vec<_>>();
     stats.sort_by_key(|stats| stats.generic);
+    for stats in &mut stats {
+        stats.resolve_lines(&module);
+    }
 
     Ok(PartialEvalResult {
         orig_module,
         module,
         stats,
+        failures,
+        specialized,
     })
 }
 
+/// Record a directive that failed with an error, or return the error
+/// if we are not to keep going. A diagnostic (an error in the program
+/// being specialized) is always recorded: it only affects this
+/// directive.
+fn record_failure(
+    failures: &mut Vec<Failure>,
+    keep_going: bool,
+    directive: &Directive,
+    err: anyhow::Error,
+) -> anyhow::Result<()> {
+    let diagnostic = err.downcast_ref::<Diagnostic>().cloned();
+    if !keep_going && diagnostic.is_none() {
+        return Err(err);
+    }
+    log::warn!("Failed to weval for directive {:?}: {:?}", directive, err);
+    failures.push(Failure {
+        directive: directive.clone(),
+        reason: format!("{:#}", err),
+        diagnostic,
+    });
+    Ok(())
+}
+
+fn export_func(module: &mut Module, name: &str, func: Func) {
+    log::info!("Exporting func {} as '{}'", func, name);
+    module.exports.push(waffle::Export {
+        name: name.to_owned(),
+        kind: waffle::ExportKind::Func(func),
+    });
+}
+
+/// Find `func` in the function table, appending it if absent, and
+/// return its table index.
+fn generic_table_index(module: &mut Module, table: Table, func: Func) -> u32 {
+    let func_table = &mut module.tables[table];
+    let func_table_elts = func_table.func_elements.as_mut().unwrap();
+    if let Some(idx) = func_table_elts.iter().position(|&f| f == func) {
+        return idx as u32;
+    }
+    let table_idx = func_table_elts.len() as u32;
+    func_table_elts.push(func);
+    if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
+        func_table.max = Some(table_idx + 1);
+    }
+    table_idx
+}
+
+/// Prepare a generic function body for specialization.
+fn prepare_generic(
+    module: &mut Module,
+    func: Func,
+    intrinsics: &Intrinsics,
+    opts: &Options,
+) -> anyhow::Result<(FunctionBody, CFGInfo, Mutex<SpecializationStats>)> {
+    if !matches!(module.funcs[func], FuncDecl::Lazy(..) | FuncDecl::Body(..)) {
+        anyhow::bail!(
+            "{} ({}) has no body to specialize",
+            func,
+            module.funcs[func].name()
+        );
+    }
+    let mut f = module.clone_and_expand_body(func)?;
+    let inlined = inline::inline_calls(module, &mut f, intrinsics, opts)?;
+    log::debug!("inlined {} call sites into {}", inlined, func);
+
+    let stats = Mutex::new(SpecializationStats::new(func, &f));
+
+    split_blocks_at_specialization_points(&mut f, intrinsics);
+
+    f.recompute_edges();
+    let cfg = CFGInfo::new(&f);
+    let cut_blocks = find_cut_blocks(&f, &cfg, intrinsics);
+
+    f.convert_to_max_ssa(Some(cut_blocks));
+
+    if opts.run_diff {
+        waffle::passes::trace::run(&mut f);
+        module.replace_body(func, f.clone());
+    }
+    Ok((f, cfg, stats))
+}
+
+/// The result of specializing a function for one directive.
+struct SpecializedFunc {
+    body: FunctionBody,
+    sig: Signature,
+    name: String,
+    block_rev_map: PerEntity<Block, (Context, Block)>,
+    contexts: Contexts,
+    /// Calls in `body` with some constant args, and the directive
+    /// that would specialize the callee on them.
+    call_sites: Vec<(Value, Directive)>,
+    /// Size of the compiled body in bytes, if measured.
+    size: Option<usize>,
+}
+
 fn partially_evaluate_func(
     module: &Module,
     generic: &FunctionBody,
     cfg: &CFGInfo,
     image: &Image,
     intrinsics: &Intrinsics,
+    opts: &Options,
     directive: &Directive,
-) -> anyhow::Result<
-    Option<(
-        FunctionBody,
-        Signature,
-        String,
-        PerEntity<Block, (Context, Block)>,
-        Contexts,
-    )>,
-> {
+) -> anyhow::Result<Result<SpecializedFunc, Cancelled>> {
     let orig_name = module.funcs[directive.func].name();
     let sig = module.funcs[directive.func].sig();
 
@@ -233,6 +623,7 @@ fn partially_evaluate_func(
     let mut evaluator = Evaluator {
         module,
         generic,
+        directive,
         intrinsics,
         image,
         cfg,
@@ -243,6 +634,13 @@ fn partially_evaluate_func(
         value_map: HashMap::default(),
         value_dep_blocks: HashMap::default(),
         reg_map: HashMap::default(),
+        mem_map: HashMap::default(),
+        specialize_calls: opts.call_specialization_depth > 0,
+        call_sites: vec![],
+        limits: Limits::new(&directive.budget, opts),
+        aborted: None,
+        bounds_updates: HashMap::default(),
+        entry_bounds_updates: HashMap::default(),
         queue: VecDeque::new(),
         queue_set: HashSet::default(),
     };
@@ -264,9 +662,8 @@ fn partially_evaluate_func(
     let pre_entry = evaluator.create_pre_entry(specialized_entry, &directive.const_params[..]);
     evaluator.func.entry = pre_entry;
 
-    let success = evaluator.evaluate()?;
-    if !success {
-        return Ok(None);
+    if let Err(cancelled) = evaluator.evaluate()? {
+        return Ok(Err(cancelled));
     }
 
     log::info!("Specialization of {:?} done", directive);
@@ -276,13 +673,47 @@ fn partially_evaluate_func(
     );
     let name = format!("{} (specialized)", orig_name);
     evaluator.func.optimize();
-    Ok(Some((
-        evaluator.func,
+
+    // Measure the output only if some budget needs it: this compiles
+    // the body an extra time.
+    let size = if evaluator.limits.max_output_bytes.is_some() || opts.max_total_bytes.is_some() {
+        let size = evaluator.func.compile()?.byte_len();
+        if evaluator
+            .limits
+            .max_output_bytes
+            .is_some_and(|max| size > max)
+        {
+            log::info!(" -> too large: {} bytes", size);
+            return Ok(Err(Cancelled::TooLarge(size)));
+        }
+        Some(size)
+    } else {
+        None
+    };
+
+    // Only keep call sites that survived in the final body (blocks
+    // may have been re-evaluated several times on the way to a
+    // fixpoint).
+    let placed = evaluator
+        .func
+        .blocks
+        .values()
+        .flat_map(|block| block.insts.iter().cloned())
+        .collect::<HashSet<_>>();
+    let call_sites = std::mem::take(&mut evaluator.call_sites)
+        .into_iter()
+        .filter(|(call, _)| placed.contains(call))
+        .collect();
+
+    Ok(Ok(SpecializedFunc {
+        body: evaluator.func,
         sig,
         name,
-        evaluator.block_rev_map,
-        evaluator.state.contexts,
-    )))
+        block_rev_map: evaluator.block_rev_map,
+        contexts: evaluator.state.contexts,
+        size,
+        call_sites,
+    }))
 }
 
 // Split at every `weval_specialize_value()` call. Requires max-SSA
@@ -409,13 +840,17 @@ fn const_operator(ty: Type, value: WasmVal) -> Option<Operator> {
         (Type::I64, WasmVal::I64(k)) => Some(Operator::I64Const { value: k }),
         (Type::F32, WasmVal::F32(k)) => Some(Operator::F32Const { value: k }),
         (Type::F64, WasmVal::F64(k)) => Some(Operator::F64Const { value: k }),
+        // TODO: V128. waffle's IR has no SIMD operators at all (no
+        // `v128.const`, no `v128.load`, no lane ops), and its
+        // frontend rejects SIMD bytecode, so there is nothing to
+        // emit or fold here until that support lands upstream.
         _ => None,
     }
 }
 
-fn store_operator(ty: Type) -> Option<Operator> {
+fn store_operator(memory: Memory, ty: Type) -> Option<Operator> {
     let memory = waffle::MemoryArg {
-        memory: waffle::Memory::new(0),
+        memory,
         align: 0,
         offset: 0,
     };
@@ -428,9 +863,9 @@ fn store_operator(ty: Type) -> Option<Operator> {
     }
 }
 
-fn load_operator(ty: Type) -> Option<Operator> {
+fn load_operator(memory: Memory, ty: Type) -> Option<Operator> {
     let memory = waffle::MemoryArg {
-        memory: waffle::Memory::new(0),
+        memory,
         align: 0,
         offset: 0,
     };
@@ -443,12 +878,169 @@ fn load_operator(ty: Type) -> Option<Operator> {
     }
 }
 
+/// For a load or store, returns its memory argument, the access size
+/// in bytes, and whether it is a store.
+fn memory_access(op: &Operator) -> Option<(waffle::MemoryArg, u32, bool)> {
+    match *op {
+        Operator::I32Load8S { memory }
+        | Operator::I32Load8U { memory }
+        | Operator::I64Load8S { memory }
+        | Operator::I64Load8U { memory } => Some((memory, 1, false)),
+        Operator::I32Load16S { memory }
+        | Operator::I32Load16U { memory }
+        | Operator::I64Load16S { memory }
+        | Operator::I64Load16U { memory } => Some((memory, 2, false)),
+        Operator::I32Load { memory }
+        | Operator::F32Load { memory }
+        | Operator::I64Load32S { memory }
+        | Operator::I64Load32U { memory } => Some((memory, 4, false)),
+        Operator::I64Load { memory } | Operator::F64Load { memory } => Some((memory, 8, false)),
+        Operator::I32Store8 { memory } | Operator::I64Store8 { memory } => Some((memory, 1, true)),
+        Operator::I32Store16 { memory } | Operator::I64Store16 { memory } => {
+            Some((memory, 2, true))
+        }
+        Operator::I32Store { memory }
+        | Operator::F32Store { memory }
+        | Operator::I64Store32 { memory } => Some((memory, 4, true)),
+        Operator::I64Store { memory } | Operator::F64Store { memory } => Some((memory, 8, true)),
+        _ => None,
+    }
+}
+
+/// For a full-width load or store (one that moves a whole value of
+/// its type, with no extension or truncation), returns that type.
+fn full_width_access_ty(op: &Operator) -> Option<Type> {
+    match op {
+        Operator::I32Load { .. } | Operator::I32Store { .. } => Some(Type::I32),
+        Operator::I64Load { .. } | Operator::I64Store { .. } => Some(Type::I64),
+        Operator::F32Load { .. } | Operator::F32Store { .. } => Some(Type::F32),
+        Operator::F64Load { .. } | Operator::F64Store { .. } => Some(Type::F64),
+        _ => None,
+    }
+}
+
+/// Size in memory of a value in the memory overlay.
+fn mem_ty_size(ty: Type) -> u32 {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 => 8,
+        _ => unreachable!("type {:?} never enters the memory overlay", ty),
+    }
+}
+
+/// The canonical NaN bit patterns. Wasm permits any arithmetic
+/// operation that produces a NaN to produce the canonical NaN, so we
+/// fold all such results to it.
+const F32_CANONICAL_NAN: u32 = 0x7fc0_0000;
+const F64_CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+fn f32_bits(x: f32) -> u32 {
+    if x.is_nan() {
+        F32_CANONICAL_NAN
+    } else {
+        x.to_bits()
+    }
+}
+
+fn f64_bits(x: f64) -> u64 {
+    if x.is_nan() {
+        F64_CANONICAL_NAN
+    } else {
+        x.to_bits()
+    }
+}
+
+fn f32_arith<F: Fn(f32, f32) -> f32>(k1: u32, k2: u32, f: F, tags: ValueTags) -> AbstractValue {
+    let result = f(f32::from_bits(k1), f32::from_bits(k2));
+    AbstractValue::Concrete(WasmVal::F32(f32_bits(result)), tags)
+}
+
+fn f64_arith<F: Fn(f64, f64) -> f64>(k1: u64, k2: u64, f: F, tags: ValueTags) -> AbstractValue {
+    let result = f(f64::from_bits(k1), f64::from_bits(k2));
+    AbstractValue::Concrete(WasmVal::F64(f64_bits(result)), tags)
+}
+
+fn f32_cmp<F: Fn(f32, f32) -> bool>(k1: u32, k2: u32, f: F, tags: ValueTags) -> AbstractValue {
+    let result = f(f32::from_bits(k1), f32::from_bits(k2));
+    AbstractValue::Concrete(WasmVal::I32(if result { 1 } else { 0 }), tags)
+}
+
+fn f64_cmp<F: Fn(f64, f64) -> bool>(k1: u64, k2: u64, f: F, tags: ValueTags) -> AbstractValue {
+    let result = f(f64::from_bits(k1), f64::from_bits(k2));
+    AbstractValue::Concrete(WasmVal::I32(if result { 1 } else { 0 }), tags)
+}
+
+/// Wasm `min`: NaN if either input is NaN, and -0 is less than +0
+/// (unlike Rust's `f64::min`).
+fn f64_wasm_min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Wasm `max`: NaN if either input is NaN, and +0 is greater than
+/// -0.
+fn f64_wasm_max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == 0.0 && b == 0.0 {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Round to nearest, ties to even. (Also exact for `f32` inputs
+/// widened to `f64`.)
+fn f64_nearest(x: f64) -> f64 {
+    let round = x.round();
+    if (x - x.trunc()).abs() != 0.5 || round % 2.0 == 0.0 {
+        round
+    } else {
+        // `round` rounded away from zero onto an odd value; step back
+        // toward zero, keeping the sign of zero results.
+        (round - x.signum()).copysign(x)
+    }
+}
+
+/// Exclusive bounds on float inputs to the trapping truncation
+/// operators: the input (after truncation) is in range iff it lies
+/// strictly between these. All bounds are exactly representable as
+/// `f64`, and `f32` inputs widen to `f64` exactly.
+const I32_TRUNC_RANGE: (f64, f64) = (-2147483649.0, 2147483648.0);
+const U32_TRUNC_RANGE: (f64, f64) = (-1.0, 4294967296.0);
+const I64_TRUNC_RANGE: (f64, f64) = (-9223372036854777856.0, 9223372036854775808.0);
+const U64_TRUNC_RANGE: (f64, f64) = (-1.0, 18446744073709551616.0);
+
+fn f64_in_trunc_range(x: f64, (lo, hi): (f64, f64)) -> bool {
+    // NaN fails both comparisons.
+    x > lo && x < hi
+}
+
 #[derive(Debug)]
 enum EvalResult {
     Unhandled,
     Elide,
     Alias(AbstractValue, Value),
     Normal(AbstractValue),
+    /// Replace the operator with another one, with new args.
+    NewOp(Operator, ListRef<Value>, AbstractValue),
 }
 impl EvalResult {
     fn is_handled(&self) -> bool {
@@ -459,25 +1051,123 @@ impl EvalResult {
     }
 }
 
-const MAX_BLOCKS: usize = 100_000;
-const MAX_VALUES: usize = 1_000_000;
+/// Fold a subtraction or equality compare of two values that are
+/// offsets from the same runtime base, which depends only on the
+/// offsets (modulo 2^32).
+fn fold_offsets(
+    op: Operator,
+    x: &AbstractValue,
+    y: &AbstractValue,
+    x_val: Value,
+    y_val: Value,
+) -> Option<AbstractValue> {
+    let (x_base, x_off) = x.base_offset(x_val)?;
+    let (y_base, y_off) = y.base_offset(y_val)?;
+    if x_base != y_base {
+        return None;
+    }
+    let diff = x_off.wrapping_sub(y_off);
+    let result = match op {
+        Operator::I32Sub => diff,
+        Operator::I32Eq => (diff == 0) as u32,
+        Operator::I32Ne => (diff != 0) as u32,
+        // Ordered compares would depend on whether `base + off` wraps
+        // around, which we don't know: a runtime base may be any
+        // integer, not only a pointer.
+        _ => return None,
+    };
+    Some(AbstractValue::Concrete(
+        WasmVal::I32(result),
+        ValueTags::default(),
+    ))
+}
+
+/// Limits for one specialization: the directive's budget, falling
+/// back to the global options.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    max_blocks: usize,
+    max_values: usize,
+    max_contexts: Option<usize>,
+    max_output_bytes: Option<usize>,
+}
+
+impl Limits {
+    fn new(budget: &Budget, opts: &Options) -> Limits {
+        Limits {
+            max_blocks: budget.max_blocks.unwrap_or(opts.max_blocks),
+            max_values: budget.max_values.unwrap_or(opts.max_values),
+            max_contexts: budget.max_contexts.or(opts.max_contexts),
+            max_output_bytes: budget.max_output_bytes.or(opts.max_output_bytes),
+        }
+    }
+}
+
+/// Why the specialization of a directive was cancelled, short of an
+/// error.
+#[derive(Clone, Copy, Debug)]
+enum Cancelled {
+    /// Too many blocks, values or contexts.
+    Budget,
+    /// The specialized body, of the given size, exceeds its budget.
+    TooLarge(usize),
+    /// Specialization reached a non-fatal `abort.specialization`
+    /// point with the given line number.
+    Aborted(u32),
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cancelled::Budget => write!(f, "exceeded the specialization budget"),
+            Cancelled::TooLarge(size) => {
+                write!(f, "specialized body of {} bytes exceeds its budget", size)
+            }
+            Cancelled::Aborted(line) => write!(f, "specialization aborted at line {}", line),
+        }
+    }
+}
+
+/// Bounds on a value may change this many times before we widen them.
+const MAX_BOUNDS_UPDATES: u32 = 2;
 
 impl<'a> Evaluator<'a> {
-    fn evaluate(&mut self) -> anyhow::Result<bool> {
+    fn evaluate(&mut self) -> anyhow::Result<Result<(), Cancelled>> {
         while let Some((orig_block, ctx, new_block)) = self.queue.pop_back() {
-            if self.func.blocks.len() > MAX_BLOCKS || self.func.values.len() > MAX_VALUES {
+            if self.func.blocks.len() > self.limits.max_blocks
+                || self.func.values.len() > self.limits.max_values
+                || self
+                    .limits
+                    .max_contexts
+                    .is_some_and(|max| self.state.contexts.len() > max)
+            {
                 log::info!(
-                    " -> too many blocks or values: {} blocks {} values",
+                    " -> too many blocks, values or contexts: {} blocks {} values {} contexts",
                     self.func.blocks.len(),
-                    self.func.values.len()
+                    self.func.values.len(),
+                    self.state.contexts.len()
                 );
-                return Ok(false);
+                return Ok(Err(Cancelled::Budget));
             }
             self.queue_set.remove(&(orig_block, ctx));
             self.evaluate_block(orig_block, ctx, new_block)?;
+            match self.aborted {
+                Some((line, true, block, inst)) => {
+                    return Err(self.diagnostic(
+                        block,
+                        Some(inst),
+                        format!(
+                            "Specialization reached a fatal abort point at line {}",
+                            line
+                        ),
+                    ));
+                }
+                Some((line, false, ..)) => return Ok(Err(Cancelled::Aborted(line))),
+                None => {}
+            }
         }
         self.finalize()?;
-        Ok(true)
+        Ok(Ok(()))
     }
 
     fn evaluate_block(
@@ -522,8 +1212,28 @@ impl<'a> Evaluator<'a> {
                     param
                 })
             },
-            &mut |mem_blockparam_map, idx| {
-                mem_blockparam_map.remove(&(ctx, orig_block, idx));
+            &mut |reg_map, idx| {
+                reg_map.remove(&(ctx, orig_block, idx));
+            },
+        )?;
+        state.flow.update_mem_at_block_entry(
+            &mut self.mem_map,
+            &mut |mem_map, addr, ty| {
+                *mem_map.entry((ctx, orig_block, addr)).or_insert_with(|| {
+                    let param = self.func.add_placeholder(ty);
+                    log::trace!(
+                        "new blockparam {} for mem addr {:?} on block {} (ctx {} orig {})",
+                        param,
+                        addr,
+                        new_block,
+                        ctx,
+                        orig_block,
+                    );
+                    param
+                })
+            },
+            &mut |mem_map, addr| {
+                mem_map.remove(&(ctx, orig_block, addr));
             },
         )?;
 
@@ -532,6 +1242,10 @@ impl<'a> Evaluator<'a> {
         // vals as well.
         self.evaluate_block_body(orig_block, &mut state, new_block)
             .map_err(|e| {
+                if e.is::<Diagnostic>() {
+                    // Already located.
+                    return e;
+                }
                 e.context(anyhow::anyhow!(
                     "Evaluating block body {} in func:\n{}",
                     orig_block,
@@ -542,7 +1256,7 @@ impl<'a> Evaluator<'a> {
         // Store the exit state at this point for later use.
         self.state.block_exit[new_block] = state.flow.clone();
 
-        self.evaluate_term(orig_block, &mut state, new_block);
+        self.evaluate_term(orig_block, &mut state, new_block)?;
 
         Ok(())
     }
@@ -556,7 +1270,7 @@ impl<'a> Evaluator<'a> {
         orig_block: Block,
         new_block: Block,
         orig_val: Value,
-    ) -> (Value, AbstractValue) {
+    ) -> anyhow::Result<(Value, AbstractValue)> {
         log::trace!(
             "using value {} at block {} in context {}",
             orig_val,
@@ -573,12 +1287,34 @@ impl<'a> Evaluator<'a> {
             let abs = &self.state.values[val];
             log::trace!(" -> found abstract  value {:?} at context {}", abs, context);
             log::trace!(" -> runtime value {}", val);
-            return (val, abs.clone());
+            return Ok((val, abs.clone()));
         }
-        panic!(
-            "Could not find value for {} in context {}",
-            orig_val, context
-        );
+        Err(self.diagnostic(
+            orig_block,
+            None,
+            format!(
+                "could not find value for {} in context {}",
+                orig_val, context
+            ),
+        ))
+    }
+
+    /// Build an error located at `inst` (or just `block`) in the
+    /// original function.
+    fn diagnostic(&self, block: Block, inst: Option<Value>, message: String) -> anyhow::Error {
+        let loc = inst
+            .map(|inst| self.generic.source_locs[inst])
+            .filter(|loc| !loc.is_invalid());
+        Diagnostic {
+            directive: self.directive.clone(),
+            func_name: self.module.funcs[self.directive.func].name().to_owned(),
+            block,
+            inst,
+            loc,
+            pos: loc.and_then(|loc| SourcePos::lookup(self.module, loc)),
+            message,
+        }
+        .into()
     }
 
     fn def_value(
@@ -599,8 +1335,17 @@ impl<'a> Evaluator<'a> {
         );
         self.value_map.insert((context, orig_val), val);
         let val_abs = &mut self.state.values[val];
-        let updated = AbstractValue::meet(val_abs, &abs);
+        let mut updated = AbstractValue::meet(val_abs, &abs);
         let changed = updated != *val_abs;
+        if changed && matches!(updated, AbstractValue::Bounded(..)) {
+            // Bounds may grow a step at a time around a loop; after a
+            // few updates, give up on precision so that we converge.
+            let updates = self.bounds_updates.entry(val).or_insert(0);
+            *updates += 1;
+            if *updates > MAX_BOUNDS_UPDATES {
+                updated = AbstractValue::widen(val_abs, &updated);
+            }
+        }
         log::debug!(
             " -> meet: cur {:?} input {:?} result {:?} (changed: {})",
             val_abs,
@@ -661,7 +1406,7 @@ impl<'a> Evaluator<'a> {
                 }
                 ValueDef::PickOutput(val, idx, ty) => {
                     // Directly transcribe.
-                    let (val, _) = self.use_value(state.context, orig_block, new_block, *val);
+                    let (val, _) = self.use_value(state.context, orig_block, new_block, *val)?;
                     Some((
                         ValueDef::PickOutput(val, *idx, *ty),
                         AbstractValue::Runtime(Some(inst), ValueTags::default()),
@@ -678,7 +1423,8 @@ impl<'a> Evaluator<'a> {
                         log::trace!(" * arg {}", arg);
                         let arg = self.generic.resolve_alias(arg);
                         log::trace!(" -> resolves to arg {}", arg);
-                        let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
+                        let (val, abs) =
+                            self.use_value(state.context, orig_block, new_block, arg)?;
                         arg_abs_values.push(abs);
                         self.func.arg_pool[arg_values][i] = val;
                     }
@@ -708,6 +1454,9 @@ impl<'a> Evaluator<'a> {
                         EvalResult::Unhandled => unreachable!(),
                         EvalResult::Alias(av, val) => Some((ValueDef::Alias(val), av)),
                         EvalResult::Elide => None,
+                        EvalResult::NewOp(new_op, new_args, av) => {
+                            Some((ValueDef::Operator(new_op, new_args, specialized_tys), av))
+                        }
                         EvalResult::Normal(AbstractValue::Concrete(bits, t)) if tys.len() == 1 => {
                             if let Some(const_op) = const_operator(tys_slice[0], bits) {
                                 Some((
@@ -744,7 +1493,8 @@ impl<'a> Evaluator<'a> {
                     let args_slice = &self.generic.arg_pool[*args];
                     for (i, &arg) in args_slice.iter().enumerate() {
                         let arg = self.generic.resolve_alias(arg);
-                        let (val, _abs) = self.use_value(state.context, orig_block, new_block, arg);
+                        let (val, _abs) =
+                            self.use_value(state.context, orig_block, new_block, arg)?;
                         self.func.arg_pool[new_args][i] = val;
                     }
                     Some((
@@ -758,8 +1508,10 @@ impl<'a> Evaluator<'a> {
                 ),
             } {
                 let result_value = self.func.add_value(result_value);
+                self.func.source_locs[result_value] = self.generic.source_locs[inst];
                 self.value_map.insert((input_ctx, inst), result_value);
                 self.func.append_to_block(new_block, result_value);
+                self.record_call_site(result_value, &arg_abs_values[..]);
 
                 self.def_value(orig_block, input_ctx, inst, result_value, result_abs);
             }
@@ -768,6 +1520,51 @@ impl<'a> Evaluator<'a> {
         Ok(())
     }
 
+    /// If `value` is a direct call with some constant args to a
+    /// function we can specialize, record the callee specialization
+    /// that the call could be redirected to.
+    fn record_call_site(&mut self, value: Value, arg_abs: &[AbstractValue]) {
+        if !self.specialize_calls {
+            return;
+        }
+        let (callee, nargs) = match &self.func.values[value] {
+            ValueDef::Operator(Operator::Call { function_index }, args, _) => {
+                (*function_index, args.len())
+            }
+            _ => return,
+        };
+        if !matches!(
+            self.module.funcs[callee],
+            FuncDecl::Lazy(..) | FuncDecl::Body(..)
+        ) {
+            return;
+        }
+        let arg_abs = &arg_abs[..nargs];
+        if !arg_abs
+            .iter()
+            .any(|abs| matches!(abs, AbstractValue::Concrete(..)))
+        {
+            return;
+        }
+        let const_params = arg_abs
+            .iter()
+            .map(|abs| match abs {
+                AbstractValue::Concrete(..) => abs.clone(),
+                _ => AbstractValue::Runtime(None, abs.tags()),
+            })
+            .collect();
+        self.call_sites.push((
+            value,
+            Directive {
+                func: callee,
+                const_params,
+                func_index_out_addr: None,
+                export: None,
+                budget: Budget::default(),
+            },
+        ));
+    }
+
     fn meet_into_block_entry(
         &mut self,
         _block: Block,
@@ -778,7 +1575,16 @@ impl<'a> Evaluator<'a> {
         let mut state = state.clone();
         state.update_across_edge();
 
-        self.state.block_entry[new_block].meet_with(&state)
+        // As for SSA values, bounds at a block entry take the hull of
+        // the incoming bounds until they have changed a few times,
+        // and are widened after that so that we converge.
+        let updates = self.entry_bounds_updates.entry(new_block).or_insert(0);
+        let widen = *updates >= MAX_BOUNDS_UPDATES;
+        let (changed, bounds_changed) = self.state.block_entry[new_block].meet_with(&state, widen);
+        if bounds_changed {
+            *updates += 1;
+        }
+        changed
     }
 
     fn context_desc(&self, ctx: Context) -> String {
@@ -888,7 +1694,7 @@ impl<'a> Evaluator<'a> {
         state: &PointState,
         target_ctx: Context,
         target: &BlockTarget,
-    ) -> BlockTarget {
+    ) -> anyhow::Result<BlockTarget> {
         let n_args = self.generic.blocks[orig_block].params.len();
         let mut args = Vec::with_capacity(n_args);
         let mut abs_args = Vec::with_capacity(n_args);
@@ -904,7 +1710,7 @@ impl<'a> Evaluator<'a> {
 
         for &arg in &target.args {
             let arg = self.generic.resolve_alias(arg);
-            let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
+            let (val, abs) = self.use_value(state.context, orig_block, new_block, arg)?;
             log::trace!(
                 "blockparam: block {} context {}: arg {} has val {} abs {:?}",
                 orig_block,
@@ -958,13 +1764,18 @@ impl<'a> Evaluator<'a> {
             self.enqueue_block_if_existing(target.block, target_ctx);
         }
 
-        BlockTarget {
+        Ok(BlockTarget {
             block: target_block,
             args,
-        }
+        })
     }
 
-    fn evaluate_term(&mut self, orig_block: Block, state: &mut PointState, new_block: Block) {
+    fn evaluate_term(
+        &mut self,
+        orig_block: Block,
+        state: &mut PointState,
+        new_block: Block,
+    ) -> anyhow::Result<()> {
         log::trace!(
             "evaluating terminator: block {} context {} specialized block {}: {:?}",
             orig_block,
@@ -981,7 +1792,8 @@ impl<'a> Evaluator<'a> {
                 ref if_true,
                 ref if_false,
             } => {
-                let (cond, abs_cond) = self.use_value(state.context, orig_block, new_block, cond);
+                let (cond, abs_cond) =
+                    self.use_value(state.context, orig_block, new_block, cond)?;
                 match abs_cond.is_const_truthy() {
                     Some(true) => Terminator::Br {
                         target: self.evaluate_block_target(
@@ -990,7 +1802,7 @@ impl<'a> Evaluator<'a> {
                             state,
                             new_context,
                             if_true,
-                        ),
+                        )?,
                     },
                     Some(false) => Terminator::Br {
                         target: self.evaluate_block_target(
@@ -999,7 +1811,7 @@ impl<'a> Evaluator<'a> {
                             state,
                             new_context,
                             if_false,
-                        ),
+                        )?,
                     },
                     None => Terminator::CondBr {
                         cond,
@@ -1009,14 +1821,14 @@ impl<'a> Evaluator<'a> {
                             state,
                             new_context,
                             if_true,
-                        ),
+                        )?,
                         if_false: self.evaluate_block_target(
                             orig_block,
                             new_block,
                             state,
                             new_context,
                             if_false,
-                        ),
+                        )?,
                     },
                 }
             }
@@ -1042,9 +1854,9 @@ impl<'a> Evaluator<'a> {
                             log::trace!(" -> created new context {} for index {}", c, i);
                             self.evaluate_block_target(orig_block, new_block, state, c, target)
                         })
-                        .collect();
+                        .collect::<anyhow::Result<_>>()?;
                     let default = targets.pop().unwrap();
-                    let (value, _) = self.use_value(state.context, orig_block, new_block, index);
+                    let (value, _) = self.use_value(state.context, orig_block, new_block, index)?;
                     Terminator::Select {
                         value,
                         targets,
@@ -1058,7 +1870,7 @@ impl<'a> Evaluator<'a> {
                             state,
                             new_context,
                             target,
-                        ),
+                        )?,
                     }
                 }
             }
@@ -1068,7 +1880,7 @@ impl<'a> Evaluator<'a> {
                 ref default,
             } => {
                 let (value, abs_value) =
-                    self.use_value(state.context, orig_block, new_block, value);
+                    self.use_value(state.context, orig_block, new_block, value)?;
                 if let Some(selector) = abs_value.is_const_u32() {
                     let selector = selector as usize;
                     let target = if selector < targets.len() {
@@ -1083,50 +1895,84 @@ impl<'a> Evaluator<'a> {
                             state,
                             new_context,
                             target,
-                        ),
+                        )?,
                     }
                 } else {
-                    let targets = targets
+                    // Targets that the selector's bounds rule out are
+                    // not evaluated; they branch to the default
+                    // instead. If the default itself is ruled out,
+                    // the highest possible target takes its place.
+                    let bounds = abs_value
+                        .int_bounds()
+                        .filter(|b| b.bits == 32)
+                        .unwrap_or(Bounds::full(32));
+                    let (targets, default) = if bounds.hi < targets.len() as u64 {
+                        let hi = bounds.hi as usize;
+                        (&targets[..hi], &targets[hi])
+                    } else {
+                        (&targets[..], default)
+                    };
+                    let live_targets = targets
                         .iter()
-                        .map(|target| {
-                            self.evaluate_block_target(
-                                orig_block,
-                                new_block,
-                                state,
-                                new_context,
-                                target,
-                            )
+                        .enumerate()
+                        .map(|(i, target)| {
+                            if bounds.contains(i as u64) {
+                                self.evaluate_block_target(
+                                    orig_block,
+                                    new_block,
+                                    state,
+                                    new_context,
+                                    target,
+                                )
+                                .map(Some)
+                            } else {
+                                Ok(None)
+                            }
                         })
-                        .collect::<Vec<_>>();
+                        .collect::<anyhow::Result<Vec<_>>>()?;
                     let default = self.evaluate_block_target(
                         orig_block,
                         new_block,
                         state,
                         new_context,
                         default,
-                    );
-                    Terminator::Select {
-                        value,
-                        targets,
-                        default,
+                    )?;
+                    if live_targets.iter().all(|target| target.is_none()) {
+                        Terminator::Br { target: default }
+                    } else {
+                        let targets = live_targets
+                            .into_iter()
+                            .map(|target| target.unwrap_or_else(|| default.clone()))
+                            .collect();
+                        Terminator::Select {
+                            value,
+                            targets,
+                            default,
+                        }
                     }
                 }
             }
             &Terminator::Return { ref values } => {
+                self.flush_mem(new_block, state, |_, _| true);
                 let values = values
                     .iter()
                     .map(|&value| {
                         self.use_value(state.context, orig_block, new_block, value)
-                            .0
+                            .map(|(value, _)| value)
                     })
-                    .collect::<Vec<_>>();
+                    .collect::<anyhow::Result<Vec<_>>>()?;
                 Terminator::Return { values }
             }
-            &Terminator::Unreachable => Terminator::Unreachable,
+            &Terminator::Unreachable => {
+                // The host may inspect memory after a trap.
+                self.flush_mem(new_block, state, |_, _| true);
+                Terminator::Unreachable
+            }
         };
         // Note: we don't use `set_terminator`, because it adds edges;
         // we add edges once, in a separate pass at the end.
         self.func.blocks[new_block].terminator = new_term;
+        Ok(())
     }
 
     fn abstract_eval(
@@ -1163,27 +2009,47 @@ impl<'a> Evaluator<'a> {
             values,
             orig_values,
             state,
-        );
+        )?;
         if intrinsic_result.is_handled() {
             log::debug!(" -> intrinsic: {:?}", intrinsic_result);
             return Ok(intrinsic_result);
         }
 
-        let reg_result =
-            self.abstract_eval_regs(orig_inst, new_block, op, abs, values, tys, state)?;
+        let reg_result = self.abstract_eval_regs(
+            orig_block, orig_inst, new_block, op, abs, values, tys, state,
+        )?;
         if reg_result.is_handled() {
             log::debug!(" -> specialization regs: {:?}", reg_result);
             return Ok(reg_result);
         }
 
-        let ret = if op.is_call() {
-            log::debug!(" -> call");
+        let mem_result = self.abstract_eval_mem(new_block, op, abs, values, state);
+        if mem_result.is_handled() {
+            log::debug!(" -> memory overlay: {:?}", mem_result);
+            return Ok(mem_result);
+        }
+
+        if let Some((function_index, args)) = self.devirtualize(op, abs, values) {
+            log::debug!(" -> devirtualized call to {}", function_index);
+            return Ok(EvalResult::NewOp(
+                Operator::Call { function_index },
+                args,
+                AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+            ));
+        }
+
+        let ret = if op.is_call() {
+            log::debug!(" -> call");
             AbstractValue::Runtime(Some(orig_inst), ValueTags::default())
         } else {
             match abs.len() {
                 0 => self.abstract_eval_nullary(orig_inst, op, state),
                 1 => self.abstract_eval_unary(orig_inst, op, &abs[0], orig_values[0], state)?,
-                2 => self.abstract_eval_binary(orig_inst, op, &abs[0], &abs[1]),
+                2 => {
+                    let (x_val, y_val) =
+                        (self.func.arg_pool[values][0], self.func.arg_pool[values][1]);
+                    self.abstract_eval_binary(orig_inst, op, &abs[0], &abs[1], x_val, y_val)
+                }
                 3 => self.abstract_eval_ternary(orig_inst, op, &abs[0], &abs[1], &abs[2]),
                 _ => {
                     let tags = abs
@@ -1197,39 +2063,116 @@ impl<'a> Evaluator<'a> {
         };
 
         log::debug!(" -> result: {:?}", ret);
+        if let AbstractValue::Offset(base, 0) = ret {
+            // E.g. `(sp - 16) + 16`: just the base again.
+            return Ok(EvalResult::Alias(self.state.values[base].clone(), base));
+        }
         Ok(EvalResult::Normal(ret))
     }
 
+    /// Resolve a `call_indirect` through a known table index to the
+    /// function in the image's table, returning the callee and the
+    /// args for a direct call. Only tables that cannot change at
+    /// runtime (see `Image::immutable_tables`) are used.
+    fn devirtualize(
+        &mut self,
+        op: Operator,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+    ) -> Option<(Func, ListRef<Value>)> {
+        let (sig_index, table_index) = match op {
+            Operator::CallIndirect {
+                sig_index,
+                table_index,
+            } => (sig_index, table_index),
+            _ => return None,
+        };
+        if !self.image.immutable_tables.contains(&table_index) {
+            return None;
+        }
+        let index = abs.last()?.is_const_u32()?;
+        let callee = *self.image.tables.get(&table_index)?.get(index as usize)?;
+        if !callee.is_valid() {
+            return None;
+        }
+        let callee_sig = self.module.funcs[callee].sig();
+        if self.module.signatures[callee_sig] != self.module.signatures[sig_index] {
+            // This will trap at runtime; leave it to do so.
+            log::debug!(
+                "call_indirect to table {} index {}: signature mismatch for {}",
+                table_index,
+                index,
+                callee
+            );
+            return None;
+        }
+
+        let nargs = self.func.arg_pool[values].len() - 1;
+        let args = self.func.arg_pool.allocate(nargs, Value::invalid());
+        for i in 0..nargs {
+            self.func.arg_pool[args][i] = self.func.arg_pool[values][i];
+        }
+        Some((callee, args))
+    }
+
     fn abstract_eval_intrinsic(
         &mut self,
         orig_block: Block,
-        _new_block: Block,
+        new_block: Block,
         orig_inst: Value,
         op: Operator,
-        _loc: SourceLoc,
+        loc: SourceLoc,
         abs: &[AbstractValue],
         values: ListRef<Value>,
         orig_values: &[Value],
         state: &mut PointState,
-    ) -> EvalResult {
-        match op {
+    ) -> anyhow::Result<EvalResult> {
+        Ok(match op {
             Operator::Call { function_index } => {
+                // The program's pointers are into the main heap, unless
+                // the `.in` variants name another memory.
                 if Some(function_index) == self.intrinsics.assume_const_memory {
+                    let heap = self.image.main_heap()?;
                     EvalResult::Alias(
-                        abs[0].with_tags(ValueTags::const_memory()),
+                        abs[0].with_tags(ValueTags::const_memory(heap)),
                         self.func.arg_pool[values][0],
                     )
                 } else if Some(function_index) == self.intrinsics.assume_const_memory_transitive {
+                    let heap = self.image.main_heap()?;
                     EvalResult::Alias(
                         abs[0].with_tags(
-                            ValueTags::const_memory() | ValueTags::const_memory_transitive(),
+                            ValueTags::const_memory(heap)
+                                | ValueTags::const_memory_transitive(heap),
                         ),
                         self.func.arg_pool[values][0],
                     )
+                } else if Some(function_index) == self.intrinsics.assume_const_memory_in {
+                    let memory = self.tagged_memory(orig_block, orig_inst, &abs[1])?;
+                    EvalResult::Alias(
+                        abs[0].with_tags(ValueTags::const_memory(memory)),
+                        self.func.arg_pool[values][0],
+                    )
+                } else if Some(function_index) == self.intrinsics.assume_const_memory_transitive_in
+                {
+                    let memory = self.tagged_memory(orig_block, orig_inst, &abs[1])?;
+                    EvalResult::Alias(
+                        abs[0].with_tags(
+                            ValueTags::const_memory(memory)
+                                | ValueTags::const_memory_transitive(memory),
+                        ),
+                        self.func.arg_pool[values][0],
+                    )
+                } else if Some(function_index) == self.intrinsics.make_symbolic_ptr {
+                    let ptr = self.func.resolve_alias(self.func.arg_pool[values][0]);
+                    log::trace!("make symbolic ptr: base {}", ptr);
+                    EvalResult::Alias(AbstractValue::SymbolicPtr(ptr, 0), ptr)
+                } else if Some(function_index) == self.intrinsics.flush_to_mem {
+                    self.flush_mem(new_block, state, |_, _| true);
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.inline {
+                    EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.push_context {
-                    let pc = abs[0]
-                        .is_const_u32()
-                        .expect("PC should not be a runtime value");
+                    let pc = self.const_arg(orig_block, orig_inst, &abs[0], "PC")?;
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
                     let child = self
                         .state
@@ -1247,28 +2190,26 @@ impl<'a> Evaluator<'a> {
                 } else if Some(function_index) == self.intrinsics.update_context {
                     log::trace!("update context at {}: PC is {:?}", orig_values[0], abs[0]);
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
+                    let pc = self.const_arg(orig_block, orig_inst, &abs[0], "PC")?;
                     let parent = self.state.contexts.pop_one_loop(instantaneous_context);
-                    let pending_context = if let Some(pc) = abs[0].is_const_u32() {
-                        Some(
-                            self.state
-                                .contexts
-                                .create(Some(parent), ContextElem::Loop(pc)),
-                        )
-                    } else {
-                        panic!("PC is a runtime value: {:?}", abs[0]);
-                    };
+                    let pending_context = Some(
+                        self.state
+                            .contexts
+                            .create(Some(parent), ContextElem::Loop(pc)),
+                    );
                     log::trace!("update context: now {:?}", pending_context);
                     state.pending_context = pending_context;
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.context_bucket {
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
-                    let bucket = abs[0].is_const_u32().unwrap();
+                    let bucket =
+                        self.const_arg(orig_block, orig_inst, &abs[0], "context bucket")?;
                     self.state.contexts.context_bucket[instantaneous_context] = Some(bucket);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.specialize_value {
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
-                    let lo = abs[1].is_const_u32().unwrap();
-                    let hi = abs[2].is_const_u32().unwrap();
+                    let lo = self.const_arg(orig_block, orig_inst, &abs[1], "lower bound")?;
+                    let hi = self.const_arg(orig_block, orig_inst, &abs[2], "upper bound")?;
                     let child = self.state.contexts.create(
                         Some(instantaneous_context),
                         ContextElem::PendingSpecialize(orig_inst, lo, hi),
@@ -1280,56 +2221,96 @@ impl<'a> Evaluator<'a> {
                         hi
                     );
                     state.pending_context = Some(child);
-                    EvalResult::Alias(abs[0].clone(), self.func.arg_pool[values][0])
+                    // The program promises the value is within
+                    // `[lo, hi]`.
+                    let promised = Bounds::range(32, lo as u64, hi as u64);
+                    let abs = match abs[0].clone() {
+                        AbstractValue::Runtime(_, tags) => AbstractValue::Bounded(promised, tags),
+                        AbstractValue::Bounded(b, tags) => match b.intersect(&promised) {
+                            Some(b) => AbstractValue::from_bounds(b, tags, None),
+                            None => AbstractValue::Bounded(b, tags),
+                        },
+                        abs => abs,
+                    };
+                    EvalResult::Alias(abs, self.func.arg_pool[values][0])
                 } else if Some(function_index) == self.intrinsics.abort_specialization {
                     let line_num = abs[0].is_const_u32().unwrap_or(0);
                     let fatal = abs[1].is_const_u32().unwrap_or(0);
-                    log::trace!("abort-specialization point: line {}", line_num);
-                    if fatal != 0 {
-                        panic!("Specialization reached a point it shouldn't have!");
-                    }
+                    log::debug!(
+                        "abort-specialization point: line {} at {}",
+                        line_num,
+                        describe_loc(self.module, loc)
+                    );
+                    // Checked (and the directive cancelled) once the
+                    // block is done.
+                    self.aborted = Some((line_num, fatal != 0, orig_block, orig_inst));
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.trace_line {
                     let line_num = abs[0].is_const_u32().unwrap_or(0);
-                    log::debug!("trace: line number {}: current context {} at block {}, pending context {:?}",
-                                line_num, state.context, orig_block, state.pending_context);
+                    log::debug!("trace: line number {} at {}: current context {} at block {}, pending context {:?}",
+                                line_num, describe_loc(self.module, loc), state.context, orig_block, state.pending_context);
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.assert_const32 {
                     log::trace!("assert_const32: abs {:?} line {:?}", abs[0], abs[1]);
                     if abs[0].is_const_u32().is_none() {
-                        panic!(
-                            "weval_assert_const32() failed: {:?}: line {:?}",
-                            abs[0], abs[1]
-                        );
+                        return Err(self.diagnostic(
+                            orig_block,
+                            Some(orig_inst),
+                            format!(
+                                "weval_assert_const32() failed: {:?}: line {:?}",
+                                abs[0], abs[1]
+                            ),
+                        ));
                     }
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.assert_const_memory {
                     log::trace!("assert_const_memory: abs {:?} line {:?}", abs[0], abs[1]);
-                    if !abs[0].tags().contains(ValueTags::const_memory()) {
-                        panic!("weval_assert_const_memory() failed: line {:?}", abs[1]);
+                    let heap = self.image.main_heap()?;
+                    if !abs[0].tags().is_const_memory(heap) {
+                        return Err(self.diagnostic(
+                            orig_block,
+                            Some(orig_inst),
+                            format!("weval_assert_const_memory() failed: line {:?}", abs[1]),
+                        ));
                     }
                     EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.print {
-                    let message_ptr = abs[0].is_const_u32().unwrap();
+                    let message_ptr =
+                        self.const_arg(orig_block, orig_inst, &abs[0], "message pointer")?;
                     let message = self
                         .image
-                        .read_str(self.image.main_heap.unwrap(), message_ptr)
-                        .unwrap();
-                    let line = abs[1].is_const_u32().unwrap();
+                        .main_heap
+                        .ok_or_else(|| anyhow::anyhow!("no main heap"))
+                        .and_then(|heap| self.image.read_str(heap, message_ptr))
+                        .map_err(|err| {
+                            self.diagnostic(
+                                orig_block,
+                                Some(orig_inst),
+                                format!("cannot read print message: {}", err),
+                            )
+                        })?;
+                    let line = abs[1].is_const_u32().unwrap_or(0);
                     let val = abs[2].clone();
-                    log::info!("print: line {}: {}: {:?}", line, message, val);
+                    log::info!(
+                        "print: line {} at {}: {}: {:?}",
+                        line,
+                        describe_loc(self.module, loc),
+                        message,
+                        val
+                    );
                     EvalResult::Elide
                 } else {
                     EvalResult::Unhandled
                 }
             }
             _ => EvalResult::Unhandled,
-        }
+        })
     }
 
     fn abstract_eval_regs(
         &mut self,
-        _inst: Value,
+        orig_block: Block,
+        inst: Value,
         _new_block: Block,
         op: Operator,
         abs: &[AbstractValue],
@@ -1341,7 +2322,7 @@ impl<'a> Evaluator<'a> {
             Operator::Call { function_index }
                 if Some(function_index) == self.intrinsics.read_reg =>
             {
-                let idx = abs[0].is_const_u64().expect("Non-constant register number");
+                let idx = self.reg_index(orig_block, inst, &abs[0])?;
                 log::trace!("load from specialization reg {}", idx);
                 match state.flow.regs.get(&idx) {
                     Some(RegValue::Value { data, abs, .. }) => {
@@ -1363,7 +2344,7 @@ impl<'a> Evaluator<'a> {
             Operator::Call { function_index }
                 if Some(function_index) == self.intrinsics.write_reg =>
             {
-                let idx = abs[0].is_const_u64().expect("Non-constant register number");
+                let idx = self.reg_index(orig_block, inst, &abs[0])?;
                 let data = self.func.arg_pool[vals][1];
                 log::trace!(
                     "store to specialization reg {} value {} abs {:?}",
@@ -1389,6 +2370,221 @@ impl<'a> Evaluator<'a> {
         Ok(EvalResult::Unhandled)
     }
 
+    /// An intrinsic arg that must be a constant.
+    fn const_arg(
+        &self,
+        orig_block: Block,
+        inst: Value,
+        abs: &AbstractValue,
+        what: &str,
+    ) -> anyhow::Result<u32> {
+        abs.is_const_u32().ok_or_else(|| {
+            self.diagnostic(
+                orig_block,
+                Some(inst),
+                format!("{} is a runtime value: {:?}", what, abs),
+            )
+        })
+    }
+
+    /// The memory named by a memory-index argument of an intrinsic,
+    /// which must be one whose pointers can be tagged.
+    fn tagged_memory(
+        &self,
+        orig_block: Block,
+        inst: Value,
+        abs: &AbstractValue,
+    ) -> anyhow::Result<Memory> {
+        let index = self.const_arg(orig_block, inst, abs, "memory index")? as usize;
+        if index >= self.module.memories.len() || index >= MAX_TAGGED_MEMORIES {
+            return Err(self.diagnostic(
+                orig_block,
+                Some(inst),
+                format!("memory {} can't be tagged as constant", index),
+            ));
+        }
+        Ok(Memory::new(index))
+    }
+
+    fn reg_index(
+        &self,
+        orig_block: Block,
+        inst: Value,
+        abs: &AbstractValue,
+    ) -> anyhow::Result<u64> {
+        abs.is_const_u64().ok_or_else(|| {
+            self.diagnostic(
+                orig_block,
+                Some(inst),
+                format!(
+                    "specialization register number is a runtime value: {:?}",
+                    abs
+                ),
+            )
+        })
+    }
+
+    fn abstract_eval_mem(
+        &mut self,
+        new_block: Block,
+        op: Operator,
+        abs: &[AbstractValue],
+        vals: ListRef<Value>,
+        state: &mut PointState,
+    ) -> EvalResult {
+        if op.is_call() {
+            // The callee may read or write any memory, except for
+            // symbolic regions, which are private to this function
+            // until flushed.
+            self.flush_mem(new_block, state, |addr, _| addr.base.is_none());
+            return EvalResult::Unhandled;
+        }
+
+        let (memarg, size, is_store) = match memory_access(&op) {
+            Some(access) => access,
+            None => return EvalResult::Unhandled,
+        };
+
+        // Only track accesses to symbolic regions, and to known
+        // addresses that are in-bounds in the initial memory (and
+        // hence can never trap, as memory never shrinks).
+        let addr = if let Some((base, off)) = abs[0].is_symbolic_ptr() {
+            Some(MemAddr {
+                memory: memarg.memory,
+                base: Some(base),
+                addr: off.wrapping_add(memarg.offset),
+            })
+        } else {
+            abs[0]
+                .is_const_u32()
+                .and_then(|base| base.checked_add(memarg.offset))
+                .map(|addr| MemAddr {
+                    memory: memarg.memory,
+                    base: None,
+                    addr,
+                })
+                .filter(|addr| self.image.can_read(addr.memory, addr.addr, size))
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                // Unknown address: may alias any known address in this
+                // memory.
+                log::trace!("memory access at unknown address: flushing overlay");
+                self.flush_mem(new_block, state, |other, _| {
+                    other.memory == memarg.memory && other.base.is_none()
+                });
+                return EvalResult::Unhandled;
+            }
+        };
+
+        let full_width_ty = full_width_access_ty(&op);
+        if is_store {
+            // Flush anything partially overwritten by this store; an
+            // exact overwrite of a same-sized entry just replaces it.
+            let exact_size = full_width_ty.map(mem_ty_size);
+            self.flush_mem(new_block, state, |other, other_ty| {
+                let other_size = mem_ty_size(other_ty);
+                addr.overlaps(size, other, other_size)
+                    && !(other == &addr && exact_size == Some(other_size))
+            });
+            if let Some(ty) = full_width_ty {
+                let data = self.func.arg_pool[vals][1];
+                log::trace!(
+                    "store to overlay at {:?}: value {} abs {:?}",
+                    addr,
+                    data,
+                    abs[1]
+                );
+                state.flow.mem.insert(
+                    addr,
+                    RegValue::Value {
+                        data,
+                        ty,
+                        abs: abs[1].clone(),
+                    },
+                );
+                return EvalResult::Elide;
+            }
+        } else {
+            if let (
+                Some(ty),
+                Some(RegValue::Value {
+                    data,
+                    abs,
+                    ty: entry_ty,
+                }),
+            ) = (full_width_ty, state.flow.mem.get(&addr))
+            {
+                if ty == *entry_ty {
+                    log::trace!(
+                        "load from overlay at {:?}: value {} abs {:?}",
+                        addr,
+                        data,
+                        abs
+                    );
+                    return EvalResult::Alias(abs.clone(), *data);
+                }
+            }
+            self.flush_mem(new_block, state, |other, other_ty| {
+                addr.overlaps(size, other, mem_ty_size(other_ty))
+            });
+        }
+
+        EvalResult::Unhandled
+    }
+
+    /// Write back (and drop) all overlay entries matching `pred`, by
+    /// emitting stores at the current end of `new_block`.
+    fn flush_mem<P: Fn(&MemAddr, Type) -> bool>(
+        &mut self,
+        new_block: Block,
+        state: &mut PointState,
+        pred: P,
+    ) {
+        let to_flush = state
+            .flow
+            .mem
+            .iter()
+            .filter_map(|(addr, val)| match val {
+                RegValue::Value { data, ty, .. } if pred(addr, *ty) => Some((*addr, *data, *ty)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (addr, data, ty) in to_flush {
+            log::trace!("flushing overlay at {:?}: value {}", addr, data);
+            state.flow.mem.remove(&addr);
+            self.emit_store(new_block, addr, data, ty);
+        }
+    }
+
+    fn emit_store(&mut self, block: Block, addr: MemAddr, data: Value, ty: Type) {
+        let i32_ty = self.func.type_pool.single(Type::I32);
+        let offset = self.func.add_value(ValueDef::Operator(
+            Operator::I32Const { value: addr.addr },
+            ListRef::default(),
+            i32_ty,
+        ));
+        self.func.append_to_block(block, offset);
+        let addr_value = match addr.base {
+            Some(base) => {
+                let args = self.func.arg_pool.double(base, offset);
+                let sum = self
+                    .func
+                    .add_value(ValueDef::Operator(Operator::I32Add, args, i32_ty));
+                self.func.append_to_block(block, sum);
+                sum
+            }
+            None => offset,
+        };
+        let store = store_operator(addr.memory, ty).unwrap();
+        let args = self.func.arg_pool.double(addr_value, data);
+        let store_value = self
+            .func
+            .add_value(ValueDef::Operator(store, args, ListRef::default()));
+        self.func.append_to_block(block, store_value);
+    }
+
     fn abstract_eval_nullary(
         &mut self,
         orig_inst: Value,
@@ -1415,6 +2611,13 @@ impl<'a> Evaluator<'a> {
         }
     }
 
+    /// Whether a load from `memory` through a pointer with `tags` can
+    /// be folded: the pointer is to constant memory, and we know the
+    /// memory's contents.
+    fn is_const_load(&self, memory: Memory, tags: ValueTags) -> bool {
+        tags.is_const_memory(memory) && self.image.memories.contains_key(&memory)
+    }
+
     fn abstract_eval_unary(
         &mut self,
         orig_inst: Value,
@@ -1485,7 +2688,7 @@ impl<'a> Evaluator<'a> {
             | (Operator::I32Load8S { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
             | (Operator::I32Load16U { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
             | (Operator::I32Load16S { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
-                if t.contains(ValueTags::const_memory()) =>
+                if self.is_const_load(memory.memory, *t) =>
             {
                 use anyhow::Context;
 
@@ -1521,8 +2724,9 @@ impl<'a> Evaluator<'a> {
                 // N.B.: memory const-ness is *not* transitive unless
                 // specified as such!  The user needs to opt in at
                 // each level of indirection.
-                let tags = if t.contains(ValueTags::const_memory_transitive()) {
-                    ValueTags::const_memory() | ValueTags::const_memory_transitive()
+                let tags = if t.is_const_memory_transitive(memory.memory) {
+                    ValueTags::const_memory(memory.memory)
+                        | ValueTags::const_memory_transitive(memory.memory)
                 } else {
                     ValueTags::default()
                 };
@@ -1538,7 +2742,7 @@ impl<'a> Evaluator<'a> {
             | (Operator::I64Load16S { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
             | (Operator::I64Load32U { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
             | (Operator::I64Load32S { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
-                if t.contains(ValueTags::const_memory()) =>
+                if self.is_const_load(memory.memory, *t) =>
             {
                 let size = match op {
                     Operator::I64Load { .. } => 8,
@@ -1567,8 +2771,9 @@ impl<'a> Evaluator<'a> {
                 // N.B.: memory const-ness is *not* transitive unless
                 // specified as such!  The user needs to opt in at
                 // each level of indirection.
-                let tags = if t.contains(ValueTags::const_memory_transitive()) {
-                    ValueTags::const_memory() | ValueTags::const_memory_transitive()
+                let tags = if t.is_const_memory_transitive(memory.memory) {
+                    ValueTags::const_memory(memory.memory)
+                        | ValueTags::const_memory_transitive(memory.memory)
                 } else {
                     ValueTags::default()
                 };
@@ -1577,11 +2782,228 @@ impl<'a> Evaluator<'a> {
                 Ok(val)
             }
 
-            // TODO: FP and SIMD
-            _ => Ok(AbstractValue::Runtime(
-                Some(orig_inst),
-                ValueTags::default(),
-            )),
+            (Operator::F32Load { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
+            | (Operator::F64Load { memory }, AbstractValue::Concrete(WasmVal::I32(k), t))
+                if self.is_const_load(memory.memory, *t) =>
+            {
+                let addr = k + memory.offset;
+                let val = match op {
+                    Operator::F32Load { .. } => {
+                        WasmVal::F32(self.image.read_u32(memory.memory, addr)?)
+                    }
+                    Operator::F64Load { .. } => {
+                        WasmVal::F64(self.image.read_u64(memory.memory, addr)?)
+                    }
+                    _ => unreachable!(),
+                };
+                let val = AbstractValue::Concrete(val, ValueTags::default());
+                log::trace!(" -> produces {:?}", val);
+                Ok(val)
+            }
+
+            // 32-bit float unary ops. `abs`, `neg` and `copysign`
+            // are pure bit operations and preserve NaN payloads;
+            // everything else canonicalizes NaN results.
+            (Operator::F32Abs, AbstractValue::Concrete(WasmVal::F32(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::F32(*k & 0x7fff_ffff), *t))
+            }
+            (Operator::F32Neg, AbstractValue::Concrete(WasmVal::F32(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::F32(*k ^ 0x8000_0000), *t))
+            }
+            (Operator::F32Ceil, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32(f32_bits(f32::from_bits(*k).ceil())), *t),
+            ),
+            (Operator::F32Floor, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32(f32_bits(f32::from_bits(*k).floor())), *t),
+            ),
+            (Operator::F32Trunc, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32(f32_bits(f32::from_bits(*k).trunc())), *t),
+            ),
+            (Operator::F32Nearest, AbstractValue::Concrete(WasmVal::F32(k), t)) => {
+                let k = f32::from_bits(*k) as f64;
+                Ok(AbstractValue::Concrete(
+                    WasmVal::F32(f32_bits(f64_nearest(k) as f32)),
+                    *t,
+                ))
+            }
+            (Operator::F32Sqrt, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32(f32_bits(f32::from_bits(*k).sqrt())), *t),
+            ),
+
+            // 64-bit float unary ops.
+            (Operator::F64Abs, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(*k & 0x7fff_ffff_ffff_ffff), *t),
+            ),
+            (Operator::F64Neg, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(*k ^ 0x8000_0000_0000_0000), *t),
+            ),
+            (Operator::F64Ceil, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(f64_bits(f64::from_bits(*k).ceil())), *t),
+            ),
+            (Operator::F64Floor, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(f64_bits(f64::from_bits(*k).floor())), *t),
+            ),
+            (Operator::F64Trunc, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(f64_bits(f64::from_bits(*k).trunc())), *t),
+            ),
+            (Operator::F64Nearest, AbstractValue::Concrete(WasmVal::F64(k), t)) => {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::F64(f64_bits(f64_nearest(f64::from_bits(*k)))),
+                    *t,
+                ))
+            }
+            (Operator::F64Sqrt, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(f64_bits(f64::from_bits(*k).sqrt())), *t),
+            ),
+
+            // Float-to-int truncations. The trapping variants are
+            // only folded when they would not trap; otherwise the
+            // trap is left to happen at runtime.
+            (Operator::I32TruncF32S, AbstractValue::Concrete(WasmVal::F32(k), t))
+                if f64_in_trunc_range(f32::from_bits(*k) as f64, I32_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I32(f32::from_bits(*k) as i32 as u32),
+                    *t,
+                ))
+            }
+            (Operator::I32TruncF32U, AbstractValue::Concrete(WasmVal::F32(k), t))
+                if f64_in_trunc_range(f32::from_bits(*k) as f64, U32_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I32(f32::from_bits(*k) as u32),
+                    *t,
+                ))
+            }
+            (Operator::I32TruncF64S, AbstractValue::Concrete(WasmVal::F64(k), t))
+                if f64_in_trunc_range(f64::from_bits(*k), I32_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I32(f64::from_bits(*k) as i32 as u32),
+                    *t,
+                ))
+            }
+            (Operator::I32TruncF64U, AbstractValue::Concrete(WasmVal::F64(k), t))
+                if f64_in_trunc_range(f64::from_bits(*k), U32_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I32(f64::from_bits(*k) as u32),
+                    *t,
+                ))
+            }
+            (Operator::I64TruncF32S, AbstractValue::Concrete(WasmVal::F32(k), t))
+                if f64_in_trunc_range(f32::from_bits(*k) as f64, I64_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I64(f32::from_bits(*k) as i64 as u64),
+                    *t,
+                ))
+            }
+            (Operator::I64TruncF32U, AbstractValue::Concrete(WasmVal::F32(k), t))
+                if f64_in_trunc_range(f32::from_bits(*k) as f64, U64_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I64(f32::from_bits(*k) as u64),
+                    *t,
+                ))
+            }
+            (Operator::I64TruncF64S, AbstractValue::Concrete(WasmVal::F64(k), t))
+                if f64_in_trunc_range(f64::from_bits(*k), I64_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I64(f64::from_bits(*k) as i64 as u64),
+                    *t,
+                ))
+            }
+            (Operator::I64TruncF64U, AbstractValue::Concrete(WasmVal::F64(k), t))
+                if f64_in_trunc_range(f64::from_bits(*k), U64_TRUNC_RANGE) =>
+            {
+                Ok(AbstractValue::Concrete(
+                    WasmVal::I64(f64::from_bits(*k) as u64),
+                    *t,
+                ))
+            }
+
+            // Saturating truncations: Rust's `as` casts from float to
+            // int saturate and map NaN to zero, exactly as Wasm
+            // specifies.
+            (Operator::I32TruncSatF32S, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I32(f32::from_bits(*k) as i32 as u32), *t),
+            ),
+            (Operator::I32TruncSatF32U, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I32(f32::from_bits(*k) as u32), *t),
+            ),
+            (Operator::I32TruncSatF64S, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I32(f64::from_bits(*k) as i32 as u32), *t),
+            ),
+            (Operator::I32TruncSatF64U, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I32(f64::from_bits(*k) as u32), *t),
+            ),
+            (Operator::I64TruncSatF32S, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I64(f32::from_bits(*k) as i64 as u64), *t),
+            ),
+            (Operator::I64TruncSatF32U, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I64(f32::from_bits(*k) as u64), *t),
+            ),
+            (Operator::I64TruncSatF64S, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I64(f64::from_bits(*k) as i64 as u64), *t),
+            ),
+            (Operator::I64TruncSatF64U, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::I64(f64::from_bits(*k) as u64), *t),
+            ),
+
+            // Int-to-float conversions (round-to-nearest-even, as
+            // Rust's `as` does).
+            (Operator::F32ConvertI32S, AbstractValue::Concrete(WasmVal::I32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32((*k as i32 as f32).to_bits()), *t),
+            ),
+            (Operator::F32ConvertI32U, AbstractValue::Concrete(WasmVal::I32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32((*k as f32).to_bits()), *t),
+            ),
+            (Operator::F32ConvertI64S, AbstractValue::Concrete(WasmVal::I64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32((*k as i64 as f32).to_bits()), *t),
+            ),
+            (Operator::F32ConvertI64U, AbstractValue::Concrete(WasmVal::I64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32((*k as f32).to_bits()), *t),
+            ),
+            (Operator::F64ConvertI32S, AbstractValue::Concrete(WasmVal::I32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64((*k as i32 as f64).to_bits()), *t),
+            ),
+            (Operator::F64ConvertI32U, AbstractValue::Concrete(WasmVal::I32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64((*k as f64).to_bits()), *t),
+            ),
+            (Operator::F64ConvertI64S, AbstractValue::Concrete(WasmVal::I64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64((*k as i64 as f64).to_bits()), *t),
+            ),
+            (Operator::F64ConvertI64U, AbstractValue::Concrete(WasmVal::I64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64((*k as f64).to_bits()), *t),
+            ),
+            (Operator::F32DemoteF64, AbstractValue::Concrete(WasmVal::F64(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F32(f32_bits(f64::from_bits(*k) as f32)), *t),
+            ),
+            (Operator::F64PromoteF32, AbstractValue::Concrete(WasmVal::F32(k), t)) => Ok(
+                AbstractValue::Concrete(WasmVal::F64(f64_bits(f32::from_bits(*k) as f64)), *t),
+            ),
+
+            // Reinterprets are bit-for-bit.
+            (Operator::F32ReinterpretI32, AbstractValue::Concrete(WasmVal::I32(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::F32(*k), *t))
+            }
+            (Operator::F64ReinterpretI64, AbstractValue::Concrete(WasmVal::I64(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::F64(*k), *t))
+            }
+            (Operator::I32ReinterpretF32, AbstractValue::Concrete(WasmVal::F32(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::I32(*k), *t))
+            }
+            (Operator::I64ReinterpretF64, AbstractValue::Concrete(WasmVal::F64(k), t)) => {
+                Ok(AbstractValue::Concrete(WasmVal::I64(*k), *t))
+            }
+
+            // TODO: SIMD (see `const_operator`).
+            (op, x) => Ok(match bounds::unary(&op, x) {
+                Some(b) => AbstractValue::from_bounds(b, ValueTags::default(), Some(orig_inst)),
+                None => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+            }),
         };
 
         result.map(|av| av.prop_sticky_tags(x))
@@ -1593,17 +3015,17 @@ impl<'a> Evaluator<'a> {
         op: Operator,
         x: &AbstractValue,
         y: &AbstractValue,
+        x_val: Value,
+        y_val: Value,
     ) -> AbstractValue {
+        if let Some(result) = fold_offsets(op, x, y, x_val, y_val) {
+            return result;
+        }
+
         let result = match (x, y) {
             (AbstractValue::Concrete(v1, tag1), AbstractValue::Concrete(v2, tag2)) => {
                 let tags = tag1.meet(*tag2);
-                let derived_ptr_tags = if tag1.contains(ValueTags::const_memory())
-                    || tag2.contains(ValueTags::const_memory())
-                {
-                    tags | ValueTags::const_memory()
-                } else {
-                    tags
-                };
+                let derived_ptr_tags = tags | (*tag1 | *tag2).derived_ptr();
                 match (op, v1, v2) {
                     // 32-bit comparisons.
                     (Operator::I32Eq, WasmVal::I32(k1), WasmVal::I32(k2)) => {
@@ -1841,11 +3263,156 @@ impl<'a> Evaluator<'a> {
                         AbstractValue::Concrete(WasmVal::I64(result), tags)
                     }
 
-                    // TODO: FP and SIMD ops.
+                    // Float comparisons. Rust's comparison operators
+                    // have IEEE semantics (any comparison with NaN is
+                    // false, except `!=`), matching Wasm.
+                    (Operator::F32Eq, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a == b, tags)
+                    }
+                    (Operator::F32Ne, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a != b, tags)
+                    }
+                    (Operator::F32Lt, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a < b, tags)
+                    }
+                    (Operator::F32Gt, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a > b, tags)
+                    }
+                    (Operator::F32Le, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a <= b, tags)
+                    }
+                    (Operator::F32Ge, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_cmp(*k1, *k2, |a, b| a >= b, tags)
+                    }
+                    (Operator::F64Eq, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a == b, tags)
+                    }
+                    (Operator::F64Ne, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a != b, tags)
+                    }
+                    (Operator::F64Lt, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a < b, tags)
+                    }
+                    (Operator::F64Gt, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a > b, tags)
+                    }
+                    (Operator::F64Le, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a <= b, tags)
+                    }
+                    (Operator::F64Ge, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_cmp(*k1, *k2, |a, b| a >= b, tags)
+                    }
+
+                    // 32-bit float arithmetic.
+                    (Operator::F32Add, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_arith(*k1, *k2, |a, b| a + b, tags)
+                    }
+                    (Operator::F32Sub, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_arith(*k1, *k2, |a, b| a - b, tags)
+                    }
+                    (Operator::F32Mul, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_arith(*k1, *k2, |a, b| a * b, tags)
+                    }
+                    (Operator::F32Div, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        f32_arith(*k1, *k2, |a, b| a / b, tags)
+                    }
+                    (Operator::F32Min, WasmVal::F32(k1), WasmVal::F32(k2)) => f32_arith(
+                        *k1,
+                        *k2,
+                        |a, b| f64_wasm_min(a as f64, b as f64) as f32,
+                        tags,
+                    ),
+                    (Operator::F32Max, WasmVal::F32(k1), WasmVal::F32(k2)) => f32_arith(
+                        *k1,
+                        *k2,
+                        |a, b| f64_wasm_max(a as f64, b as f64) as f32,
+                        tags,
+                    ),
+                    (Operator::F32Copysign, WasmVal::F32(k1), WasmVal::F32(k2)) => {
+                        AbstractValue::Concrete(
+                            WasmVal::F32((k1 & 0x7fff_ffff) | (k2 & 0x8000_0000)),
+                            tags,
+                        )
+                    }
+
+                    // 64-bit float arithmetic.
+                    (Operator::F64Add, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, |a, b| a + b, tags)
+                    }
+                    (Operator::F64Sub, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, |a, b| a - b, tags)
+                    }
+                    (Operator::F64Mul, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, |a, b| a * b, tags)
+                    }
+                    (Operator::F64Div, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, |a, b| a / b, tags)
+                    }
+                    (Operator::F64Min, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, f64_wasm_min, tags)
+                    }
+                    (Operator::F64Max, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        f64_arith(*k1, *k2, f64_wasm_max, tags)
+                    }
+                    (Operator::F64Copysign, WasmVal::F64(k1), WasmVal::F64(k2)) => {
+                        AbstractValue::Concrete(
+                            WasmVal::F64(
+                                (k1 & 0x7fff_ffff_ffff_ffff) | (k2 & 0x8000_0000_0000_0000),
+                            ),
+                            tags,
+                        )
+                    }
+
+                    // TODO: SIMD ops (see `const_operator`).
                     _ => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
                 }
             }
-            _ => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+            // Pointer arithmetic within a symbolic region.
+            (
+                AbstractValue::SymbolicPtr(base, off),
+                AbstractValue::Concrete(WasmVal::I32(k), _),
+            ) => match op {
+                Operator::I32Add => AbstractValue::SymbolicPtr(*base, off.wrapping_add(*k)),
+                Operator::I32Sub => AbstractValue::SymbolicPtr(*base, off.wrapping_sub(*k)),
+                _ => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+            },
+            (
+                AbstractValue::Concrete(WasmVal::I32(k), _),
+                AbstractValue::SymbolicPtr(base, off),
+            ) if matches!(op, Operator::I32Add) => {
+                AbstractValue::SymbolicPtr(*base, off.wrapping_add(*k))
+            }
+            // Offsets from a runtime base.
+            (AbstractValue::Offset(base, off), AbstractValue::Concrete(WasmVal::I32(k), _))
+                if matches!(op, Operator::I32Add | Operator::I32Sub) =>
+            {
+                match op {
+                    Operator::I32Add => AbstractValue::Offset(*base, off.wrapping_add(*k)),
+                    _ => AbstractValue::Offset(*base, off.wrapping_sub(*k)),
+                }
+            }
+            (AbstractValue::Concrete(WasmVal::I32(k), _), AbstractValue::Offset(base, off))
+                if matches!(op, Operator::I32Add) =>
+            {
+                AbstractValue::Offset(*base, off.wrapping_add(*k))
+            }
+            (AbstractValue::Runtime(..), AbstractValue::Concrete(WasmVal::I32(k), _))
+                if matches!(op, Operator::I32Add | Operator::I32Sub) =>
+            {
+                match op {
+                    Operator::I32Add => AbstractValue::Offset(x_val, *k),
+                    _ => AbstractValue::Offset(x_val, k.wrapping_neg()),
+                }
+            }
+            (AbstractValue::Concrete(WasmVal::I32(k), _), AbstractValue::Runtime(..))
+                if matches!(op, Operator::I32Add) =>
+            {
+                AbstractValue::Offset(y_val, *k)
+            }
+            _ => match bounds::binary(&op, x, y) {
+                Some(b) => AbstractValue::from_bounds(b, ValueTags::default(), Some(orig_inst)),
+                None => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+            },
         };
 
         result.prop_sticky_tags(x).prop_sticky_tags(y)
@@ -1859,15 +3426,24 @@ impl<'a> Evaluator<'a> {
         y: &AbstractValue,
         z: &AbstractValue,
     ) -> AbstractValue {
-        let result = match (op, z) {
-            (Operator::Select, AbstractValue::Concrete(v, _t))
-            | (Operator::TypedSelect { .. }, AbstractValue::Concrete(v, _t)) => {
-                if v.is_truthy() {
+        let result = match (op, z.is_const_truthy()) {
+            (Operator::Select, Some(truthy)) | (Operator::TypedSelect { .. }, Some(truthy)) => {
+                if truthy {
                     x.clone()
                 } else {
                     y.clone()
                 }
             }
+            (Operator::Select, None) | (Operator::TypedSelect { .. }, None) => {
+                match (x.int_bounds(), y.int_bounds()) {
+                    (Some(b1), Some(b2)) if b1.bits == b2.bits => AbstractValue::from_bounds(
+                        b1.meet(&b2),
+                        ValueTags::default(),
+                        Some(orig_inst),
+                    ),
+                    _ => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
+                }
+            }
             _ => AbstractValue::Runtime(Some(orig_inst), ValueTags::default()),
         };
 
@@ -1906,6 +3482,28 @@ impl<'a> Evaluator<'a> {
                 self.func.set_alias(orig_val, val_blockparam);
             }
 
+            // Overlay entries that conflicted at this block are not
+            // carried in; they are flushed on the incoming edges
+            // instead (see `flush_mem_on_edges`).
+            let mem_addrs = succ_state
+                .mem
+                .iter()
+                .filter_map(|(&addr, val)| val.ty().map(|ty| (addr, ty)))
+                .collect::<Vec<_>>();
+            for &(addr, ty) in &mem_addrs {
+                let val_blockparam = self.func.add_blockparam(block, ty);
+                let orig_val = *self.mem_map.get(&(ctx, orig_block, addr)).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "placeholder val not found for mem addr {:?} at block {} (ctx {} orig {})",
+                        addr,
+                        block,
+                        ctx,
+                        orig_block,
+                    )
+                })?;
+                self.func.set_alias(orig_val, val_blockparam);
+            }
+
             for pred_idx in 0..self.func.blocks[block].preds.len() {
                 let pred = self.func.blocks[block].preds[pred_idx];
                 let pred_state = &self.state.block_exit[pred];
@@ -1919,12 +3517,66 @@ impl<'a> Evaluator<'a> {
                             target.args.push(pred_val);
                         });
                 }
+                for &(addr, _) in &mem_addrs {
+                    let pred_val = pred_state.mem.get(&addr).unwrap().value().unwrap();
+                    self.func.blocks[pred]
+                        .terminator
+                        .update_target(pred_succ_idx, |target| {
+                            target.args.push(pred_val);
+                        });
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Write back overlay entries that are live at the end of a
+    /// block but not carried into a successor. The stores go at the
+    /// end of the predecessor if it has only that one successor, or
+    /// else in a new block splitting the edge.
+    fn flush_mem_on_edges(&mut self) {
+        let mut edges = vec![];
+        for block in self.func.blocks.iter() {
+            for (succ_idx, &succ) in self.func.blocks[block].succs.iter().enumerate() {
+                let succ_mem = &self.state.block_entry[succ].mem;
+                let to_flush = self.state.block_exit[block]
+                    .mem
+                    .iter()
+                    .filter_map(|(addr, val)| match val {
+                        RegValue::Value { data, ty, .. }
+                            if !matches!(succ_mem.get(addr), Some(RegValue::Merge { .. })) =>
+                        {
+                            Some((*addr, *data, *ty))
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if !to_flush.is_empty() {
+                    edges.push((block, succ_idx, succ, to_flush));
+                }
+            }
+        }
+
+        for (pred, succ_idx, succ, to_flush) in edges {
+            let store_block = if self.func.blocks[pred].succs.len() == 1 {
+                pred
+            } else {
+                self.func.split_edge(pred, succ, succ_idx)
+            };
+            log::trace!(
+                "flushing {} overlay entries on edge {} -> {} in {}",
+                to_flush.len(),
+                pred,
+                succ,
+                store_block
+            );
+            for (addr, data, ty) in to_flush {
+                self.emit_store(store_block, addr, data, ty);
+            }
+        }
+    }
+
     fn create_pre_entry(&mut self, specialized_entry: Block, args: &[AbstractValue]) -> Block {
         // Define a pre-entry block that ties supposedly
         // specialized-on-constant params to actual constants. This "bakes
@@ -1982,6 +3634,7 @@ impl<'a> Evaluator<'a> {
         self.func.recompute_edges();
 
         self.add_blockparam_reg_args()?;
+        self.flush_mem_on_edges();
 
         #[cfg(debug_assertions)]
         self.func.validate().unwrap();
@@ -1989,3 +3642,39 @@ impl<'a> Evaluator<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_offsets_does_not_order() {
+        // `x + 1 <u x` is true for x = 0xffff_ffff, so it must not fold.
+        let x = Value::new(0);
+        let x_abs = AbstractValue::Runtime(None, ValueTags::default());
+        let x_plus_1 = Value::new(1);
+        let x_plus_1_abs = AbstractValue::Offset(x, 1);
+        for op in [
+            Operator::I32LtU,
+            Operator::I32LeU,
+            Operator::I32GtU,
+            Operator::I32GeU,
+        ] {
+            assert!(fold_offsets(op, &x_plus_1_abs, &x_abs, x_plus_1, x).is_none());
+        }
+
+        let fold = |op| fold_offsets(op, &x_plus_1_abs, &x_abs, x_plus_1, x);
+        assert!(matches!(
+            fold(Operator::I32Sub),
+            Some(AbstractValue::Concrete(WasmVal::I32(1), _))
+        ));
+        assert!(matches!(
+            fold(Operator::I32Eq),
+            Some(AbstractValue::Concrete(WasmVal::I32(0), _))
+        ));
+        assert!(matches!(
+            fold(Operator::I32Ne),
+            Some(AbstractValue::Concrete(WasmVal::I32(1), _))
+        ));
+    }
+}
@@ -12,8 +12,16 @@
 //!   - If a return value, then the first arg is returned. Assert that types
 //!     match accordingly. Generate a drop (`0x1a`) for all remaining args.
 //!   - Otherwise, if any args, generate drops for all args.
+//!   - Pad the replacement with `nop`s to the length of the call, so
+//!     that code offsets within the function don't change.
+//! - Carry DWARF debug info over, fixing up the offsets of function
+//!   bodies that moved, and of code after any call that grew.
+//! - Keep other custom sections as-is, except those we are asked to
+//!   strip. Transcribe all of the name section, remapping function
+//!   indices in the names of functions, locals and labels.
 
 use fxhash::FxHashMap;
+use wasm_encoder::Encode;
 use wasmparser::{ElementKind, ExternalKind, Parser, Payload, Type, TypeRef, ValType};
 
 #[derive(Clone, Debug)]
@@ -35,6 +43,8 @@ impl FuncRemap {
 struct Rewrite {
     func_remap: FxHashMap<u32, FuncRemap>,
     func_types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    /// Names of custom sections to drop.
+    strip_custom_sections: Vec<String>,
 }
 
 fn gen_replacement_bytecode(
@@ -69,7 +79,84 @@ fn parser_to_encoder_ty(ty: wasmparser::ValType) -> wasm_encoder::ValType {
     }
 }
 
+/// Whether `name` is a section of the object-file linking convention
+/// (`linking`, `reloc.*`). These hold function indices and code
+/// offsets that we change without updating them, so we always drop
+/// them: the output is not relocatable anyway.
+fn is_linking_section(name: &str) -> bool {
+    name == "linking" || name.starts_with("reloc.")
+}
+
 impl Rewrite {
+    fn is_stripped(&self, name: &str) -> bool {
+        self.strip_custom_sections.iter().any(|s| s == name)
+            || (crate::dwarf::is_debug_section(name) && self.strips_debug_info())
+    }
+
+    /// Stripping any DWARF section strips all of them.
+    fn strips_debug_info(&self) -> bool {
+        self.strip_custom_sections
+            .iter()
+            .any(|s| crate::dwarf::is_debug_section(s))
+    }
+
+    /// Transcribe the name section, subsection by subsection (they
+    /// must stay in order). Only function indices change.
+    fn rewrite_names(
+        &self,
+        data: &[u8],
+        offset: usize,
+    ) -> anyhow::Result<wasm_encoder::NameSection> {
+        let mut names = wasm_encoder::NameSection::new();
+        for subsection in wasmparser::NameSectionReader::new(data, offset)? {
+            match subsection? {
+                wasmparser::Name::Module { name, .. } => names.module(name),
+                wasmparser::Name::Function(map) => {
+                    let mut func_names = wasm_encoder::NameMap::new();
+                    for name in map {
+                        let name = name?;
+                        if let Some(&FuncRemap::Index(new_index)) = self.func_remap.get(&name.index)
+                        {
+                            func_names.append(new_index, name.name);
+                        }
+                    }
+                    names.functions(&func_names);
+                }
+                // Locals and labels are named per function.
+                wasmparser::Name::Local(map) => names.locals(&self.remap_indirect_names(map)?),
+                wasmparser::Name::Label(map) => names.labels(&self.remap_indirect_names(map)?),
+                wasmparser::Name::Type(map) => names.types(&copy_names(map)?),
+                wasmparser::Name::Table(map) => names.tables(&copy_names(map)?),
+                wasmparser::Name::Memory(map) => names.memories(&copy_names(map)?),
+                wasmparser::Name::Global(map) => names.globals(&copy_names(map)?),
+                wasmparser::Name::Element(map) => names.elements(&copy_names(map)?),
+                wasmparser::Name::Data(map) => names.data(&copy_names(map)?),
+                // We can't tell whether an unknown subsection refers to
+                // functions by index, so it can't be kept safely.
+                wasmparser::Name::Unknown { ty, .. } => {
+                    log::debug!("dropping unknown name subsection {}", ty);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Transcribe per-function names (of locals or labels) for the
+    /// functions that remain, under their new indices.
+    fn remap_indirect_names(
+        &self,
+        names: wasmparser::IndirectNameMap,
+    ) -> anyhow::Result<wasm_encoder::IndirectNameMap> {
+        let mut out = wasm_encoder::IndirectNameMap::new();
+        for naming in names {
+            let naming = naming?;
+            if let Some(&FuncRemap::Index(new_index)) = self.func_remap.get(&naming.index) {
+                out.append(new_index, &copy_names(naming.names)?);
+            }
+        }
+        Ok(out)
+    }
+
     pub fn process(mut self, module: &[u8]) -> anyhow::Result<Vec<u8>> {
         let parser = Parser::new(0);
         let mut out = wasm_encoder::Module::new();
@@ -77,6 +164,7 @@ impl Rewrite {
         let mut out_func_idx = 0;
         let mut num_funcs = 0;
         let mut num_funcs_emitted = 0;
+        let mut growth = vec![];
         let mut out_code_section = wasm_encoder::CodeSection::new();
 
         for payload in parser.parse_all(module) {
@@ -248,51 +336,59 @@ impl Rewrite {
                     // become errors; intrinsics can only be used for
                     // ordinary calls.)
 
-                    let mut locals = vec![];
-                    for local in code.get_locals_reader()? {
-                        let (count, ty) = local?;
-                        let ty = parser_to_encoder_ty(ty);
-                        locals.push((count, ty));
-                    }
-
-                    let mut func = wasm_encoder::Function::new(locals);
+                    // Body bytes (the locals are copied as-is). We
+                    // keep each rewritten instruction at its original
+                    // length where we can, so that code offsets (and
+                    // the DWARF that refers to them) stay valid, and
+                    // note where we can't.
+                    let mut body = vec![];
                     let mut last_offset = code.range().start;
-                    let mut skip = true;
+                    let mut skip = false;
                     for entry in code.get_operators_reader()?.into_iter_with_offsets() {
                         let (op, offset) = entry?;
                         if !skip {
-                            func.raw(module[last_offset..offset].iter().cloned());
+                            body.extend_from_slice(&module[last_offset..offset]);
                         }
                         last_offset = offset;
 
                         skip = match op {
-                            wasmparser::Operator::Call { function_index } => {
+                            wasmparser::Operator::Call { function_index }
+                            | wasmparser::Operator::ReturnCall { function_index } => {
+                                let opcode = module[offset];
+                                let orig_len = 1 + leb128_len(&module[offset + 1..]);
+                                let start = body.len();
                                 match self.func_remap.get(&function_index).unwrap() {
                                     FuncRemap::Index(i) => {
-                                        func.instruction(&wasm_encoder::Instruction::Call(*i));
+                                        body.push(opcode);
+                                        write_padded_leb128(&mut body, *i, orig_len - 1);
                                     }
                                     FuncRemap::InlinedBytecode(ops) => {
                                         for op in ops {
-                                            func.instruction(op);
+                                            op.encode(&mut body);
                                         }
-                                    }
-                                }
-                                true
-                            }
-                            wasmparser::Operator::ReturnCall { function_index } => {
-                                match self.func_remap.get(&function_index).unwrap() {
-                                    FuncRemap::Index(i) => {
-                                        func.instruction(&wasm_encoder::Instruction::ReturnCall(
-                                            *i,
-                                        ));
-                                    }
-                                    FuncRemap::InlinedBytecode(ops) => {
-                                        for op in ops {
-                                            func.instruction(op);
+                                        if let wasmparser::Operator::ReturnCall { .. } = op {
+                                            wasm_encoder::Instruction::Return.encode(&mut body);
+                                        }
+                                        while body.len() - start < orig_len {
+                                            wasm_encoder::Instruction::Nop.encode(&mut body);
+                                        }
+                                        if body.len() - start > orig_len {
+                                            log::debug!(
+                                                "intrinsic call at {:#x} grew from {} to {} bytes",
+                                                offset,
+                                                orig_len,
+                                                body.len() - start
+                                            );
                                         }
-                                        func.instruction(&wasm_encoder::Instruction::Return);
                                     }
                                 }
+                                if body.len() - start > orig_len {
+                                    growth.push(crate::dwarf::Growth {
+                                        body: num_funcs_emitted as usize,
+                                        at: (offset + orig_len - code.range().start) as u64,
+                                        by: (body.len() - start - orig_len) as u64,
+                                    });
+                                }
                                 true
                             }
                             wasmparser::Operator::RefFunc { function_index }
@@ -309,10 +405,10 @@ impl Rewrite {
                         };
                     }
                     if !skip {
-                        func.raw(module[last_offset..code.range().end].iter().cloned());
+                        body.extend_from_slice(&module[last_offset..code.range().end]);
                     }
 
-                    out_code_section.function(&func);
+                    out_code_section.raw(&body[..]);
                     num_funcs_emitted += 1;
 
                     if num_funcs_emitted == num_funcs {
@@ -322,32 +418,26 @@ impl Rewrite {
                     false
                 }
 
+                Payload::CustomSection(reader) if self.is_stripped(reader.name()) => {
+                    log::debug!("stripping custom section {}", reader.name());
+                    false
+                }
+                Payload::CustomSection(reader) if is_linking_section(reader.name()) => {
+                    log::debug!("dropping linking section {}", reader.name());
+                    false
+                }
                 Payload::CustomSection(reader) if reader.name() == "name" => {
-                    let name_reader =
-                        wasmparser::NameSectionReader::new(reader.data(), reader.data_offset())?;
-                    let mut names = wasm_encoder::NameSection::new();
-                    let mut func_names = wasm_encoder::NameMap::new();
-                    for subsection in name_reader {
-                        let subsection = subsection?;
-                        match subsection {
-                            wasmparser::Name::Function(names) => {
-                                for name in names {
-                                    let name = name?;
-                                    if let Some(&FuncRemap::Index(new_index)) =
-                                        self.func_remap.get(&name.index)
-                                    {
-                                        func_names.append(new_index, name.name);
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                    names.functions(&func_names);
+                    let names = self.rewrite_names(reader.data(), reader.data_offset())?;
                     out.section(&names);
                     false
                 }
-                Payload::CustomSection(..) => false,
+                // Carried over (with fixed-up code offsets) below.
+                Payload::CustomSection(reader) if crate::dwarf::is_debug_section(reader.name()) => {
+                    false
+                }
+                // Anything else carries no indices we know of, so we
+                // keep it as-is.
+                Payload::CustomSection(..) => true,
                 _ => true,
             };
 
@@ -360,11 +450,205 @@ impl Rewrite {
             }
         }
 
-        Ok(out.finish())
+        let bytes = out.finish();
+        if self.strips_debug_info() {
+            return Ok(bytes);
+        }
+        // Code offsets may have moved; carry the debug info over.
+        crate::dwarf::carry_over(module, bytes, &[], &growth[..])
+    }
+}
+
+/// Transcribe a name map whose indices don't change.
+fn copy_names(names: wasmparser::NameMap) -> anyhow::Result<wasm_encoder::NameMap> {
+    let mut out = wasm_encoder::NameMap::new();
+    for name in names {
+        let name = name?;
+        out.append(name.index, name.name);
+    }
+    Ok(out)
+}
+
+/// The length of the LEB128 number at the start of `bytes`.
+fn leb128_len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|&b| b & 0x80 == 0).unwrap() + 1
+}
+
+/// Write `value` as a LEB128 number padded to `len` bytes (or as
+/// many as it needs, if more).
+fn write_padded_leb128(out: &mut Vec<u8>, mut value: u32, len: usize) {
+    let mut written = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        written += 1;
+        if value == 0 && written >= len {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
     }
 }
 
-pub fn filter(module: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let rewrite = Rewrite::default();
+/// Strip intrinsics from `module`, and the custom sections named in
+/// `strip_custom_sections`. Other custom sections are kept, except
+/// for the linking sections.
+pub fn filter(module: &[u8], strip_custom_sections: &[String]) -> anyhow::Result<Vec<u8>> {
+    let rewrite = Rewrite {
+        strip_custom_sections: strip_custom_sections.to_vec(),
+        ..Rewrite::default()
+    };
     rewrite.process(module)
 }
+
+/// Append to `output` the custom sections of `input` that we don't
+/// regenerate (the name section and DWARF), except for those named in
+/// `strip_custom_sections` and the linking sections. The backend emits no custom sections of
+/// its own besides names.
+pub fn append_custom_sections(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    strip_custom_sections: &[String],
+) -> anyhow::Result<()> {
+    for payload in Parser::new(0).parse_all(input) {
+        if let Payload::CustomSection(reader) = payload? {
+            let name = reader.name();
+            if name == "name"
+                || crate::dwarf::is_debug_section(name)
+                || strip_custom_sections.iter().any(|s| s == name)
+            {
+                continue;
+            }
+            if is_linking_section(name) {
+                log::debug!("dropping linking section {}", name);
+                continue;
+            }
+            crate::dwarf::append_custom_section(output, name, reader.data());
+        }
+    }
+    Ok(())
+}
+
+/// Give `output`, the backend's encoding of the specialized `input`,
+/// the rest of `input`'s names. The backend names only functions.
+/// Specialization adds functions but renumbers nothing, so the names
+/// of types, tables, memories, globals, elements and data segments
+/// are copied as they are; names of locals and labels are kept for
+/// functions whose bodies the backend did not re-encode.
+pub fn merge_names(input: &[u8], output: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let input_names = match name_section(input)? {
+        Some(names) => names,
+        None => return Ok(output.to_vec()),
+    };
+    let input_bodies = function_bodies(input)?;
+    let output_bodies = function_bodies(output)?;
+    let unchanged = |func: u32| {
+        matches!(
+            (input_bodies.get(&func), output_bodies.get(&func)),
+            (Some(a), Some(b)) if a == b
+        )
+    };
+    let keep_unchanged = |map: wasmparser::IndirectNameMap| -> anyhow::Result<_> {
+        let mut out = wasm_encoder::IndirectNameMap::new();
+        for naming in map {
+            let naming = naming?;
+            if unchanged(naming.index) {
+                out.append(naming.index, &copy_names(naming.names)?);
+            }
+        }
+        Ok(out)
+    };
+
+    let mut names = wasm_encoder::NameSection::new();
+    if let Some(module_name) = module_name(input_names)? {
+        names.module(module_name);
+    }
+    let mut out = wasm_encoder::Module::new();
+    for payload in Parser::new(0).parse_all(output) {
+        let payload = payload?;
+        if let Payload::CustomSection(reader) = &payload {
+            if reader.name() == "name" {
+                for subsection in
+                    wasmparser::NameSectionReader::new(reader.data(), reader.data_offset())?
+                {
+                    if let wasmparser::Name::Function(map) = subsection? {
+                        names.functions(&copy_names(map)?);
+                    }
+                }
+                continue;
+            }
+        }
+        if let Some((id, range)) = payload.as_section() {
+            out.section(&wasm_encoder::RawSection {
+                id,
+                data: &output[range],
+            });
+        }
+    }
+
+    let (data, offset) = input_names;
+    for subsection in wasmparser::NameSectionReader::new(data, offset)? {
+        match subsection? {
+            // Done above.
+            wasmparser::Name::Module { .. } | wasmparser::Name::Function(_) => {}
+            wasmparser::Name::Local(map) => names.locals(&keep_unchanged(map)?),
+            wasmparser::Name::Label(map) => names.labels(&keep_unchanged(map)?),
+            wasmparser::Name::Type(map) => names.types(&copy_names(map)?),
+            wasmparser::Name::Table(map) => names.tables(&copy_names(map)?),
+            wasmparser::Name::Memory(map) => names.memories(&copy_names(map)?),
+            wasmparser::Name::Global(map) => names.globals(&copy_names(map)?),
+            wasmparser::Name::Element(map) => names.elements(&copy_names(map)?),
+            wasmparser::Name::Data(map) => names.data(&copy_names(map)?),
+            wasmparser::Name::Unknown { ty, .. } => {
+                log::debug!("dropping unknown name subsection {}", ty);
+            }
+        }
+    }
+    out.section(&names);
+    Ok(out.finish())
+}
+
+/// The contents and offset of a module's name section, if any.
+fn name_section(module: &[u8]) -> anyhow::Result<Option<(&[u8], usize)>> {
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CustomSection(reader) = payload? {
+            if reader.name() == "name" {
+                return Ok(Some((reader.data(), reader.data_offset())));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The module name in a name section, if any.
+fn module_name((data, offset): (&[u8], usize)) -> anyhow::Result<Option<&str>> {
+    for subsection in wasmparser::NameSectionReader::new(data, offset)? {
+        if let wasmparser::Name::Module { name, .. } = subsection? {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+/// The encoded bodies of a module's functions, by function index.
+fn function_bodies(module: &[u8]) -> anyhow::Result<FxHashMap<u32, &[u8]>> {
+    let mut bodies = FxHashMap::default();
+    let mut func_idx = 0;
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let TypeRef::Func(_) = import?.ty {
+                        func_idx += 1;
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                bodies.insert(func_idx, &module[body.range()]);
+                func_idx += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(bodies)
+}
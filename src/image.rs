@@ -1,14 +1,20 @@
 //! Static module image summary.
 
 use crate::value::WasmVal;
-use std::collections::BTreeMap;
-use waffle::{Func, Global, Memory, MemoryData, MemorySegment, Module, Table};
+use std::collections::{BTreeMap, BTreeSet};
+use waffle::entity::EntityRef;
+use waffle::{
+    ExportKind, Func, Global, ImportKind, Memory, MemoryData, MemorySegment, Module, Table,
+};
 
 #[derive(Clone, Debug)]
 pub struct Image {
     pub memories: BTreeMap<Memory, MemImage>,
     pub globals: BTreeMap<Global, WasmVal>,
     pub tables: BTreeMap<Table, Vec<Func>>,
+    /// Tables whose contents cannot change at runtime, so that calls
+    /// through them may be resolved from `tables`.
+    pub immutable_tables: BTreeSet<Table>,
     pub stack_pointer: Option<Global>,
     pub main_heap: Option<Memory>,
     pub main_table: Option<Table>,
@@ -18,13 +24,98 @@ pub struct Image {
 pub struct MemImage {
     pub image: Vec<u8>,
     pub len: usize,
+    /// The memory's maximum size in bytes, if limited.
+    pub max_len: Option<usize>,
+    /// Where to place the next constant data we allocate, in pages
+    /// we have added to the end of the memory.
+    alloc_next: Option<usize>,
+    /// The constant data we have allocated, by contents, so that
+    /// equal data gets one address.
+    allocated: BTreeMap<Vec<u8>, u32>,
 }
 
-pub fn build_image(module: &Module) -> anyhow::Result<Image> {
+const WASM_PAGE: usize = 1 << 16;
+
+/// The names (export names, or names in the name section) by which
+/// we find the shadow-stack pointer, the main heap and the
+/// function-pointer table. Each defaults to the name the toolchains
+/// use, and can be overridden.
+#[derive(Clone, Debug)]
+pub struct RootNames {
+    pub stack_pointer: Option<String>,
+    pub heap: Option<String>,
+    pub table: Option<String>,
+}
+
+impl RootNames {
+    const STACK_POINTER: &'static str = "__stack_pointer";
+    const HEAP: &'static str = "memory";
+    const TABLE: &'static str = "__indirect_function_table";
+}
+
+pub fn build_image(module: &Module, names: &RootNames) -> anyhow::Result<Image> {
+    // The contents of imported memories are not known until runtime.
+    let imported_memories = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Memory(mem) => Some(mem),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    let section_names = SectionNames::parse(module.orig_bytes)?;
+    let stack_pointer = discover(
+        "stack pointer",
+        names.stack_pointer.as_deref(),
+        RootNames::STACK_POINTER,
+        |name| {
+            module.exports.iter().find_map(|export| match export.kind {
+                ExportKind::Global(global) if export.name == name => Some(global),
+                _ => None,
+            })
+        },
+        |name| section_names.find(&section_names.globals[..], name),
+        // There is no telling which global is the stack pointer.
+        None,
+    )?;
+    let main_heap = discover(
+        "heap memory",
+        names.heap.as_deref(),
+        RootNames::HEAP,
+        |name| {
+            module.exports.iter().find_map(|export| match export.kind {
+                ExportKind::Memory(mem) if export.name == name => Some(mem),
+                _ => None,
+            })
+        },
+        |name| section_names.find(&section_names.memories[..], name),
+        only(module.memories.iter()),
+    )?;
+    let main_table = discover(
+        "function table",
+        names.table.as_deref(),
+        RootNames::TABLE,
+        |name| {
+            module.exports.iter().find_map(|export| match export.kind {
+                ExportKind::Table(table) if export.name == name => Some(table),
+                _ => None,
+            })
+        },
+        |name| section_names.find(&section_names.tables[..], name),
+        only(module.tables.iter()),
+    )?;
+    log::debug!(
+        "stack pointer {:?}, heap {:?}, function table {:?}",
+        stack_pointer,
+        main_heap,
+        main_table
+    );
+
     Ok(Image {
         memories: module
             .memories
             .entries()
+            .filter(|(id, _)| !imported_memories.contains(id))
             .flat_map(|(id, mem)| maybe_mem_image(mem).map(|image| (id, image)))
             .collect(),
         globals: module
@@ -40,17 +131,139 @@ pub fn build_image(module: &Module) -> anyhow::Result<Image> {
             .entries()
             .map(|(id, data)| (id, data.func_elements.clone().unwrap_or(vec![])))
             .collect(),
-        // HACK: assume first global is shadow stack pointer.
-        stack_pointer: module.globals.iter().next(),
-        // HACK: assume first memory is main heap.
-        main_heap: module.memories.iter().next(),
-        // HACK: assume first table is used for function pointers.
-        main_table: module.tables.iter().next(),
+        immutable_tables: immutable_tables(module)?,
+        stack_pointer,
+        main_heap,
+        main_table,
     })
 }
 
+/// The tables that are neither imported nor exported and that no
+/// function body writes (or might write, through `elem.drop`'s
+/// effect on a later `table.init`), so that their contents are the
+/// ones in the module.
+fn immutable_tables(module: &Module) -> anyhow::Result<BTreeSet<Table>> {
+    use wasmparser::Operator;
+    let mut tables = module.tables.iter().collect::<BTreeSet<_>>();
+    for import in &module.imports {
+        if let ImportKind::Table(table) = import.kind {
+            tables.remove(&table);
+        }
+    }
+    for export in &module.exports {
+        if let ExportKind::Table(table) = export.kind {
+            tables.remove(&table);
+        }
+    }
+    for payload in wasmparser::Parser::new(0).parse_all(module.orig_bytes) {
+        let body = match payload? {
+            wasmparser::Payload::CodeSectionEntry(body) => body,
+            _ => continue,
+        };
+        let mut ops = body.get_operators_reader()?;
+        while !ops.eof() {
+            let table = match ops.read()? {
+                Operator::TableSet { table }
+                | Operator::TableGrow { table }
+                | Operator::TableFill { table }
+                | Operator::TableInit { table, .. }
+                | Operator::TableCopy {
+                    dst_table: table, ..
+                } => table,
+                Operator::ElemDrop { .. } => {
+                    tables.clear();
+                    continue;
+                }
+                _ => continue,
+            };
+            tables.remove(&Table::new(table as usize));
+        }
+    }
+    Ok(tables)
+}
+
+/// Find an entity by export name and by name-section name. If the
+/// two disagree, we can't tell which is meant, so it's an error. A
+/// name given explicitly must be found; otherwise, if the default
+/// name is not found, we use `fallback`.
+fn discover<T: EntityRef + std::fmt::Display>(
+    what: &str,
+    explicit: Option<&str>,
+    default: &str,
+    by_export: impl Fn(&str) -> Option<T>,
+    by_name: impl Fn(&str) -> Option<T>,
+    fallback: Option<T>,
+) -> anyhow::Result<Option<T>> {
+    let name = explicit.unwrap_or(default);
+    match (by_export(name), by_name(name)) {
+        (Some(exported), Some(named)) if exported != named => anyhow::bail!(
+            "{} '{}' is ambiguous: {} is exported as '{}', but {} has that name",
+            what,
+            name,
+            exported,
+            name,
+            named
+        ),
+        (Some(found), _) | (_, Some(found)) => Ok(Some(found)),
+        (None, None) if explicit.is_some() => {
+            anyhow::bail!("no {} exported or named as '{}'", what, name)
+        }
+        (None, None) => Ok(fallback),
+    }
+}
+
+/// The only item, if there is exactly one.
+fn only<T>(mut iter: impl Iterator<Item = T>) -> Option<T> {
+    let first = iter.next()?;
+    match iter.next() {
+        Some(_) => None,
+        None => Some(first),
+    }
+}
+
+/// The names of globals, memories and tables in the name section.
+#[derive(Default)]
+struct SectionNames {
+    globals: Vec<(u32, String)>,
+    memories: Vec<(u32, String)>,
+    tables: Vec<(u32, String)>,
+}
+
+impl SectionNames {
+    fn parse(module: &[u8]) -> anyhow::Result<SectionNames> {
+        let mut names = SectionNames::default();
+        for payload in wasmparser::Parser::new(0).parse_all(module) {
+            let reader = match payload? {
+                wasmparser::Payload::CustomSection(reader) if reader.name() == "name" => reader,
+                _ => continue,
+            };
+            for subsection in
+                wasmparser::NameSectionReader::new(reader.data(), reader.data_offset())?
+            {
+                let (map, out) = match subsection? {
+                    wasmparser::Name::Global(map) => (map, &mut names.globals),
+                    wasmparser::Name::Memory(map) => (map, &mut names.memories),
+                    wasmparser::Name::Table(map) => (map, &mut names.tables),
+                    _ => continue,
+                };
+                for naming in map {
+                    let naming = naming?;
+                    out.push((naming.index, naming.name.to_owned()));
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn find<T: EntityRef>(&self, names: &[(u32, String)], name: &str) -> Option<T> {
+        names
+            .iter()
+            .find(|(_, n)| n == name)
+            .map(|&(index, _)| T::new(index as usize))
+    }
+}
+
 fn maybe_mem_image(mem: &MemoryData) -> Option<MemImage> {
-    const WASM_PAGE: usize = 1 << 16;
     let len = mem.initial_pages * WASM_PAGE;
     let mut image = vec![0; len];
 
@@ -59,17 +272,70 @@ fn maybe_mem_image(mem: &MemoryData) -> Option<MemImage> {
             .copy_from_slice(&segment.data[..]);
     }
 
-    Some(MemImage { image, len })
+    Some(MemImage {
+        image,
+        len,
+        max_len: mem.maximum_pages.map(|pages| pages * WASM_PAGE),
+        alloc_next: None,
+        allocated: BTreeMap::new(),
+    })
 }
 
-pub fn update(module: &mut Module, im: &Image) {
+/// Write the final memory images back to the module as data
+/// segments. Segments whose contents are still correct are kept;
+/// everything else is emitted as dense runs of bytes, split wherever
+/// at least `min_gap` bytes in a row need no initialization (because
+/// they are zero, or written correctly by a kept segment).
+pub fn update(module: &mut Module, im: &Image, min_gap: usize) {
     for (&mem_id, mem) in &im.memories {
-        module.memories[mem_id].segments.clear();
-        module.memories[mem_id].segments.push(MemorySegment {
-            offset: 0,
-            data: mem.image.clone(),
+        // The image may have grown to hold data we allocated.
+        let memory = &mut module.memories[mem_id];
+        memory.initial_pages = std::cmp::max(memory.initial_pages, mem.len / WASM_PAGE);
+
+        let segments = std::mem::take(&mut module.memories[mem_id].segments);
+        let mut base = vec![0; mem.len];
+        let mut kept = vec![];
+        for segment in segments {
+            let range = segment.offset..(segment.offset + segment.data.len());
+            if mem.image[range.clone()] == segment.data[..] {
+                base[range].copy_from_slice(&segment.data[..]);
+                kept.push(segment);
+            }
+        }
+        let runs = dense_runs(&mem.image[..], &base[..], min_gap);
+        log::debug!(
+            "memory {}: kept {} data segments, added {} ({} bytes)",
+            mem_id,
+            kept.len(),
+            runs.len(),
+            runs.iter().map(|run| run.data.len()).sum::<usize>()
+        );
+        kept.extend(runs);
+        module.memories[mem_id].segments = kept;
+    }
+}
+
+/// Segments covering every byte where `image` differs from `base`,
+/// merging runs separated by fewer than `min_gap` equal bytes.
+fn dense_runs(image: &[u8], base: &[u8], min_gap: usize) -> Vec<MemorySegment> {
+    let mut segments = vec![];
+    let mut pos = 0;
+    while let Some(start) = (pos..image.len()).find(|&i| image[i] != base[i]) {
+        let mut last = start;
+        for i in (start + 1)..image.len() {
+            if image[i] != base[i] {
+                last = i;
+            } else if i - last >= min_gap {
+                break;
+            }
+        }
+        segments.push(MemorySegment {
+            offset: start,
+            data: image[start..=last].to_vec(),
         });
+        pos = last + 1;
     }
+    segments
 }
 
 impl Image {
@@ -90,8 +356,65 @@ impl Image {
             .ok_or_else(|| anyhow::anyhow!("no main heap"))
     }
 
+    fn memory(&self, id: Memory) -> anyhow::Result<&MemImage> {
+        self.memories
+            .get(&id)
+            .ok_or_else(|| anyhow::anyhow!("no image of {}", id))
+    }
+
+    fn memory_mut(&mut self, id: Memory) -> anyhow::Result<&mut MemImage> {
+        self.memories
+            .get_mut(&id)
+            .ok_or_else(|| anyhow::anyhow!("no image of {}", id))
+    }
+
+    /// Place `data` in `memory`, in pages added to the end of its
+    /// initial size, and return its address. Equal data is placed
+    /// once.
+    ///
+    /// The program's allocator must not hand out these pages. An
+    /// allocator that has already started (e.g. in a Wizer snapshot)
+    /// only grows memory from its current end, but one that starts
+    /// later may claim all memory up to the current size: wasi-libc's
+    /// dlmalloc takes everything from `__heap_base` on. Constant data
+    /// is only safe in such modules if the specialized code does not
+    /// read it at runtime.
+    pub fn alloc_const_bytes(&mut self, memory: Memory, data: &[u8]) -> anyhow::Result<u32> {
+        const ALIGN: usize = 16;
+        let image = self.memory_mut(memory)?;
+        if let Some(&addr) = image.allocated.get(data) {
+            return Ok(addr);
+        }
+        let addr = image.alloc_next.unwrap_or(image.len);
+        let end = addr + data.len();
+        let len = std::cmp::max(image.len, end.div_ceil(WASM_PAGE) * WASM_PAGE);
+        if len as u64 > 1 << 32 || image.max_len.is_some_and(|max| len > max) {
+            anyhow::bail!(
+                "no room in {} for {} bytes of constant data",
+                memory,
+                data.len()
+            );
+        }
+        image.image.resize(len, 0);
+        image.len = len;
+        image.image[addr..end].copy_from_slice(data);
+        image.alloc_next = Some(end.div_ceil(ALIGN) * ALIGN);
+        image.allocated.insert(data.to_vec(), addr as u32);
+        log::debug!(
+            "placed {} bytes of constant data at 0x{:x}",
+            data.len(),
+            addr
+        );
+        Ok(addr as u32)
+    }
+
+    pub fn main_table(&self) -> anyhow::Result<Table> {
+        self.main_table
+            .ok_or_else(|| anyhow::anyhow!("no function table"))
+    }
+
     pub fn read_u8(&self, id: Memory, addr: u32) -> anyhow::Result<u8> {
-        let image = self.memories.get(&id).unwrap();
+        let image = self.memory(id)?;
         image
             .image
             .get(addr as usize)
@@ -100,7 +423,7 @@ impl Image {
     }
 
     pub fn read_u16(&self, id: Memory, addr: u32) -> anyhow::Result<u16> {
-        let image = self.memories.get(&id).unwrap();
+        let image = self.memory(id)?;
         let addr = addr as usize;
         if (addr + 2) > image.len {
             anyhow::bail!("Out of bounds");
@@ -110,7 +433,7 @@ impl Image {
     }
 
     pub fn read_u32(&self, id: Memory, addr: u32) -> anyhow::Result<u32> {
-        let image = self.memories.get(&id).unwrap();
+        let image = self.memory(id)?;
         let addr = addr as usize;
         if (addr + 4) > image.len {
             anyhow::bail!("Out of bounds");
@@ -154,8 +477,18 @@ impl Image {
         Ok(std::str::from_utf8(&bytes[..])?.to_owned())
     }
 
+    pub fn read_bytes(&self, id: Memory, addr: u32, len: u32) -> anyhow::Result<&[u8]> {
+        let image = self.memory(id)?;
+        let start = addr as usize;
+        let end = start + len as usize;
+        image
+            .image
+            .get(start..end)
+            .ok_or_else(|| anyhow::anyhow!("Out of bounds"))
+    }
+
     pub fn write_u8(&mut self, id: Memory, addr: u32, value: u8) -> anyhow::Result<()> {
-        let image = self.memories.get_mut(&id).unwrap();
+        let image = self.memory_mut(id)?;
         *image
             .image
             .get_mut(addr as usize)
@@ -164,7 +497,7 @@ impl Image {
     }
 
     pub fn write_u32(&mut self, id: Memory, addr: u32, value: u32) -> anyhow::Result<()> {
-        let image = self.memories.get_mut(&id).unwrap();
+        let image = self.memory_mut(id)?;
         let addr = addr as usize;
         if (addr + 4) > image.len {
             anyhow::bail!("Out of bounds");
@@ -175,9 +508,7 @@ impl Image {
     }
 
     pub fn func_ptr(&self, idx: u32) -> anyhow::Result<Func> {
-        let table = self
-            .main_table
-            .ok_or_else(|| anyhow::anyhow!("no main table"))?;
+        let table = self.main_table()?;
         Ok(self
             .tables
             .get(&table)
@@ -187,3 +518,75 @@ impl Image {
             .ok_or_else(|| anyhow::anyhow!("func ptr out of bounds"))?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(offset: usize, data: &[u8]) -> MemorySegment {
+        MemorySegment {
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn dense_runs_merge_gaps() {
+        let image = [0, 1, 0, 0, 2, 0, 0, 0, 3];
+        let base = [0; 9];
+        assert_eq!(
+            dense_runs(&image[..], &base[..], 3),
+            vec![run(1, &[1, 0, 0, 2]), run(8, &[3])]
+        );
+        assert_eq!(
+            dense_runs(&image[..], &base[..], 4),
+            vec![run(1, &[1, 0, 0, 2, 0, 0, 0, 3])]
+        );
+        assert_eq!(dense_runs(&base[..], &base[..], 3), vec![]);
+    }
+
+    #[test]
+    fn dense_runs_min_gap_zero() {
+        // No gap is too small to split at.
+        let image = [0, 1, 1, 0, 2];
+        let base = [0; 5];
+        assert_eq!(
+            dense_runs(&image[..], &base[..], 0),
+            vec![run(1, &[1, 1]), run(4, &[2])]
+        );
+    }
+
+    #[test]
+    fn update_keeps_unchanged_segments() {
+        // Two overlapping segments, the second of which was written
+        // to after it was applied.
+        let bytes = [0, 0x61, 0x73, 0x6d, 1, 0, 0, 0, 5, 3, 1, 0, 1];
+        let mut module = Module::from_wasm_bytes(&bytes[..], &Default::default()).unwrap();
+        let memory = Memory::new(0);
+        let first = run(0x10, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let second = run(0x14, &[5, 6, 7, 8, 9, 10, 11, 12]);
+        module.memories[memory].segments = vec![first.clone(), second];
+        let mut mem = maybe_mem_image(&module.memories[memory]).unwrap();
+        mem.image[0x1a] = 0xff;
+        let expected = mem.image.clone();
+        let im = Image {
+            memories: [(memory, mem)].into_iter().collect(),
+            globals: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            immutable_tables: BTreeSet::new(),
+            stack_pointer: None,
+            main_heap: Some(memory),
+            main_table: None,
+        };
+
+        update(&mut module, &im, 4);
+        let segments = &module.memories[memory].segments;
+        assert_eq!(segments, &vec![first, run(0x18, &[9, 10, 0xff, 12])]);
+        let mut image = vec![0; expected.len()];
+        for segment in segments {
+            image[segment.offset..(segment.offset + segment.data.len())]
+                .copy_from_slice(&segment.data[..]);
+        }
+        assert_eq!(image, expected);
+    }
+}
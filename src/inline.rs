@@ -0,0 +1,319 @@
+//! Inlining of direct calls into generic function bodies.
+//!
+//! We inline on the generic body, before it is prepared for
+//! specialization, so that the callee's code is then specialized in
+//! place, under the caller's context and with its abstract arguments,
+//! just like the rest of the caller.
+//!
+//! A callee is inlined if it is small enough (`--inline-max-insts`)
+//! or if it is marked with the `inline` intrinsic. Inlined code may
+//! itself have calls inlined, up to `--inline-max-depth` levels,
+//! which also bounds recursion.
+
+use crate::intrinsics::Intrinsics;
+use crate::Options;
+use fxhash::FxHashMap as HashMap;
+use std::collections::VecDeque;
+use waffle::entity::PerEntity;
+use waffle::{
+    Block, BlockTarget, Func, FuncDecl, FunctionBody, Module, Operator, Terminator, Value, ValueDef,
+};
+
+struct Inliner<'a> {
+    module: &'a Module<'a>,
+    intrinsics: &'a Intrinsics,
+    opts: &'a Options,
+    /// Expanded bodies of callees, or `None` if not inlinable.
+    callees: HashMap<Func, Option<FunctionBody>>,
+}
+
+/// Inlines eligible direct calls in `func`. Returns the number of
+/// call sites inlined.
+pub fn inline_calls(
+    module: &Module,
+    func: &mut FunctionBody,
+    intrinsics: &Intrinsics,
+    opts: &Options,
+) -> anyhow::Result<usize> {
+    if opts.inline_max_depth == 0 {
+        return Ok(0);
+    }
+
+    let mut inliner = Inliner {
+        module,
+        intrinsics,
+        opts,
+        callees: HashMap::default(),
+    };
+
+    // Queue of (block, inlining depth of the code in that block).
+    let mut queue = func
+        .blocks
+        .iter()
+        .map(|block| (block, 0))
+        .collect::<VecDeque<_>>();
+    let mut inlined = 0;
+    while let Some((block, depth)) = queue.pop_front() {
+        if depth >= opts.inline_max_depth {
+            continue;
+        }
+        for i in 0..func.blocks[block].insts.len() {
+            let inst = func.blocks[block].insts[i];
+            let callee = match &func.values[inst] {
+                ValueDef::Operator(Operator::Call { function_index }, ..) => *function_index,
+                _ => continue,
+            };
+            let callee_body = match inliner.callee_body(callee)? {
+                Some(body) => body.clone(),
+                None => continue,
+            };
+
+            log::debug!(
+                "inlining call {} to {} at depth {}",
+                inst,
+                module.funcs[callee].name(),
+                depth
+            );
+            let (callee_blocks, cont) = inliner.inline_call(func, block, i, callee, &callee_body);
+            inlined += 1;
+
+            // The rest of the block moved to `cont`, which is at
+            // the same depth; the callee's blocks are one deeper.
+            queue.extend(callee_blocks.into_iter().map(|b| (b, depth + 1)));
+            queue.push_back((cont, depth));
+            break;
+        }
+    }
+
+    if inlined > 0 {
+        log::trace!("After inlining:\n{}\n", func.display_verbose("| ", None));
+    }
+    Ok(inlined)
+}
+
+impl<'a> Inliner<'a> {
+    fn callee_body(&mut self, callee: Func) -> anyhow::Result<Option<&FunctionBody>> {
+        if !self.callees.contains_key(&callee) {
+            let body = match &self.module.funcs[callee] {
+                FuncDecl::Lazy(..) | FuncDecl::Body(..) => {
+                    let body = self.module.clone_and_expand_body(callee)?;
+                    let insts = body.blocks.values().map(|b| b.insts.len()).sum::<usize>();
+                    let small =
+                        self.opts.inline_max_insts > 0 && insts <= self.opts.inline_max_insts;
+                    if small || self.is_marked_inline(&body) {
+                        Some(body)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            self.callees.insert(callee, body);
+        }
+        Ok(self.callees.get(&callee).unwrap().as_ref())
+    }
+
+    fn is_marked_inline(&self, body: &FunctionBody) -> bool {
+        body.blocks.values().any(|block| {
+            block
+                .insts
+                .iter()
+                .any(|&inst| self.is_inline_marker(&body.values[inst]))
+        })
+    }
+
+    fn is_inline_marker(&self, def: &ValueDef) -> bool {
+        match def {
+            ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                Some(*function_index) == self.intrinsics.inline
+            }
+            _ => false,
+        }
+    }
+
+    /// Inline the call at `block.insts[index]` to `callee`, whose
+    /// expanded body is `body`. Returns the blocks copied from the
+    /// callee and the continuation block that receives the rest of
+    /// `block`.
+    fn inline_call(
+        &self,
+        func: &mut FunctionBody,
+        block: Block,
+        index: usize,
+        callee: Func,
+        body: &FunctionBody,
+    ) -> (Vec<Block>, Block) {
+        let call = func.blocks[block].insts[index];
+        let (args, result_tys) = match &func.values[call] {
+            ValueDef::Operator(_, args, tys) => {
+                (func.arg_pool[*args].to_vec(), func.type_pool[*tys].to_vec())
+            }
+            _ => unreachable!(),
+        };
+        let callee_name = self.module.funcs[callee].name();
+
+        // Split the block after the call; the rest of the block
+        // goes to a continuation that receives the results as
+        // blockparams.
+        let cont = func.add_block();
+        let rest = func.blocks[block].insts.split_off(index + 1);
+        func.blocks[block].insts.pop();
+        for &inst in &rest {
+            func.value_blocks[inst] = cont;
+        }
+        func.blocks[cont].insts = rest;
+        func.blocks[cont].terminator = std::mem::take(&mut func.blocks[block].terminator);
+        func.blocks[cont].desc = format!(
+            "Continuation of {} after inlined call to {}",
+            func.blocks[block].desc, callee_name
+        );
+        self.bind_results(func, call, cont, &result_tys[..]);
+
+        // Copy the callee's blocks and values.
+        let mut block_map: PerEntity<Block, Block> = PerEntity::default();
+        let mut callee_blocks = vec![];
+        for callee_block in body.blocks.iter() {
+            let new_block = func.add_block();
+            func.blocks[new_block].desc = format!(
+                "Inlined from {}: {}",
+                callee_name, body.blocks[callee_block].desc
+            );
+            block_map[callee_block] = new_block;
+            callee_blocks.push(new_block);
+        }
+        let mut value_map: PerEntity<Value, Value> = PerEntity::default();
+        for value in body.values.iter() {
+            value_map[value] = func.add_value(ValueDef::None);
+        }
+        for value in body.values.iter() {
+            let new_value = value_map[value];
+            let def = match body.values[value] {
+                ValueDef::BlockParam(b, i, ty) => {
+                    func.value_blocks[new_value] = block_map[b];
+                    ValueDef::BlockParam(block_map[b], i, ty)
+                }
+                ValueDef::Operator(op, args, tys) => {
+                    let args = func
+                        .arg_pool
+                        .from_iter(body.arg_pool[args].iter().map(|&arg| value_map[arg]));
+                    let tys = func
+                        .type_pool
+                        .from_iter(body.type_pool[tys].iter().cloned());
+                    ValueDef::Operator(op, args, tys)
+                }
+                ValueDef::PickOutput(v, i, ty) => ValueDef::PickOutput(value_map[v], i, ty),
+                ValueDef::Alias(v) => ValueDef::Alias(value_map[v]),
+                ValueDef::Placeholder(ty) => ValueDef::Placeholder(ty),
+                ValueDef::Trace(id, args) => {
+                    let args = func
+                        .arg_pool
+                        .from_iter(body.arg_pool[args].iter().map(|&arg| value_map[arg]));
+                    ValueDef::Trace(id, args)
+                }
+                ValueDef::None => ValueDef::None,
+            };
+            func.values[new_value] = def;
+            func.source_locs[new_value] = body.source_locs[value];
+        }
+
+        for (callee_block, def) in body.blocks.entries() {
+            let new_block = block_map[callee_block];
+            func.blocks[new_block].params = def
+                .params
+                .iter()
+                .map(|&(ty, param)| (ty, value_map[param]))
+                .collect();
+            for &inst in &def.insts {
+                // The marker has served its purpose.
+                if self.is_inline_marker(&body.values[inst]) {
+                    continue;
+                }
+                func.append_to_block(new_block, value_map[inst]);
+            }
+            func.blocks[new_block].terminator = match &def.terminator {
+                Terminator::Return { values } => Terminator::Br {
+                    target: BlockTarget {
+                        block: cont,
+                        args: values.iter().map(|&v| value_map[v]).collect(),
+                    },
+                },
+                term => {
+                    let mut term = term.clone();
+                    match &mut term {
+                        Terminator::CondBr { cond, .. } => *cond = value_map[*cond],
+                        Terminator::Select { value, .. } => *value = value_map[*value],
+                        _ => {}
+                    }
+                    term.update_targets(|target| {
+                        target.block = block_map[target.block];
+                        for arg in &mut target.args {
+                            *arg = value_map[*arg];
+                        }
+                    });
+                    term
+                }
+            };
+        }
+
+        // Branch from the call site into the callee's entry.
+        func.blocks[block].terminator = Terminator::Br {
+            target: BlockTarget {
+                block: block_map[body.entry],
+                args,
+            },
+        };
+
+        (callee_blocks, cont)
+    }
+
+    /// Turn the call's results into blockparams of `cont`: a
+    /// single-result call value becomes the blockparam itself, and
+    /// for multi-result calls, so do the `PickOutput`s.
+    fn bind_results(
+        &self,
+        func: &mut FunctionBody,
+        call: Value,
+        cont: Block,
+        result_tys: &[waffle::Type],
+    ) {
+        let mut results: Vec<Option<Value>> = vec![None; result_tys.len()];
+        if result_tys.len() == 1 {
+            results[0] = Some(call);
+        } else if result_tys.len() > 1 {
+            for value in func.values.iter() {
+                if let ValueDef::PickOutput(from, i, _) = func.values[value] {
+                    if from == call {
+                        match results[i as usize] {
+                            None => results[i as usize] = Some(value),
+                            Some(first) => func.values[value] = ValueDef::Alias(first),
+                        }
+                    }
+                }
+            }
+        }
+
+        for (i, &ty) in result_tys.iter().enumerate() {
+            match results[i] {
+                Some(value) => {
+                    func.values[value] = ValueDef::BlockParam(cont, i as u32, ty);
+                    func.blocks[cont].params.push((ty, value));
+                    func.value_blocks[value] = cont;
+                }
+                None => {
+                    func.add_blockparam(cont, ty);
+                }
+            }
+        }
+        if result_tys.is_empty() {
+            func.values[call] = ValueDef::None;
+        }
+        // The `PickOutput`s immediately followed the call, so are
+        // now at the start of `cont`; they are no longer instructions.
+        func.blocks[cont].insts.retain(|&inst| {
+            !matches!(
+                func.values[inst],
+                ValueDef::BlockParam(..) | ValueDef::Alias(..)
+            )
+        });
+    }
+}
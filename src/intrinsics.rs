@@ -6,6 +6,8 @@ use waffle::{ExportKind, Func, ImportKind, Module, Operator, Terminator, Type, V
 pub struct Intrinsics {
     pub assume_const_memory: Option<Func>,
     pub assume_const_memory_transitive: Option<Func>,
+    pub assume_const_memory_in: Option<Func>,
+    pub assume_const_memory_transitive_in: Option<Func>,
     pub read_reg: Option<Func>,
     pub write_reg: Option<Func>,
     pub push_context: Option<Func>,
@@ -18,6 +20,9 @@ pub struct Intrinsics {
     pub assert_const_memory: Option<Func>,
     pub specialize_value: Option<Func>,
     pub print: Option<Func>,
+    pub make_symbolic_ptr: Option<Func>,
+    pub flush_to_mem: Option<Func>,
+    pub inline: Option<Func>,
 }
 
 impl Intrinsics {
@@ -35,6 +40,18 @@ impl Intrinsics {
                 &[Type::I32],
                 &[Type::I32],
             ),
+            assume_const_memory_in: find_imported_intrinsic(
+                module,
+                "assume.const.memory.in",
+                &[Type::I32, Type::I32],
+                &[Type::I32],
+            ),
+            assume_const_memory_transitive_in: find_imported_intrinsic(
+                module,
+                "assume.const.memory.transitive.in",
+                &[Type::I32, Type::I32],
+                &[Type::I32],
+            ),
             read_reg: find_imported_intrinsic(module, "read.reg", &[Type::I64], &[Type::I64]),
             write_reg: find_imported_intrinsic(module, "write.reg", &[Type::I64, Type::I64], &[]),
             push_context: find_imported_intrinsic(module, "push.context", &[Type::I32], &[]),
@@ -72,6 +89,14 @@ impl Intrinsics {
                 &[Type::I32, Type::I32, Type::I32],
                 &[],
             ),
+            make_symbolic_ptr: find_imported_intrinsic(
+                module,
+                "make.symbolic.ptr",
+                &[Type::I32],
+                &[Type::I32],
+            ),
+            flush_to_mem: find_imported_intrinsic(module, "flush.to.mem", &[], &[]),
+            inline: find_imported_intrinsic(module, "inline", &[], &[]),
         }
     }
 }
@@ -0,0 +1,330 @@
+//! The WebAssembly partial evaluator.
+//!
+//! weval specializes functions in a Wasm module on constant
+//! arguments, as requested by the module itself (via the
+//! `weval.pending.head` request list, e.g. in a Wizer snapshot) or by
+//! the embedder. The `weval` command-line tool is a thin wrapper
+//! around this library:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let input = std::fs::read("snapshot.wasm")?;
+//! let mut options = weval::Options::default();
+//! options.strip_intrinsics = true;
+//! let output = weval::Weval::new()
+//!     .options(options)
+//!     .progress(|p| eprintln!("{}/{}", p.done, p.total))
+//!     .run(&input[..])?;
+//! std::fs::write("specialized.wasm", &output.bytes[..])?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![allow(dead_code)]
+
+use waffle::entity::EntityRef;
+use waffle::FuncDecl;
+
+mod bounds;
+pub mod diagnostic;
+mod diff;
+pub mod directive;
+mod directive_file;
+mod dwarf;
+mod eval;
+mod filter;
+mod image;
+mod inline;
+mod intrinsics;
+mod state;
+pub mod stats;
+pub mod value;
+
+pub use directive::{Budget, Directive};
+pub use eval::Failure;
+pub use stats::SpecializationStats;
+
+/// Options for partial evaluation.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The export name or name-section name of the memory used as
+    /// the program's heap (holding the request list, and what
+    /// pointers passed to intrinsics point into). Defaults to
+    /// `memory`, or else the only memory.
+    pub heap_memory: Option<String>,
+    /// The export name or name-section name of the shadow-stack
+    /// pointer global. Defaults to `__stack_pointer`.
+    pub stack_pointer: Option<String>,
+    /// The export name or name-section name of the table holding
+    /// function pointers. Defaults to `__indirect_function_table`,
+    /// or else the only table.
+    pub function_table: Option<String>,
+    /// Custom sections to drop from the output. Other custom sections
+    /// are kept, except `linking` and `reloc.*`, which are always
+    /// dropped. Naming any `.debug_*` section drops all DWARF debug
+    /// info.
+    pub strip_custom_sections: Vec<String>,
+    /// Remove the weval intrinsics (imports and calls) from the
+    /// output, e.g. when the input was wizened with the stubs.
+    pub strip_intrinsics: bool,
+    /// Run IR in interpreter differentially, before and after
+    /// wevaling, comparing trace outputs. `Weval::run` fails on a
+    /// mismatch.
+    pub run_diff: bool,
+    /// Inline direct calls to functions with at most this many
+    /// instructions before specializing (0 inlines only functions
+    /// marked with the `inline` intrinsic).
+    pub inline_max_insts: usize,
+    /// Maximum depth of nested inlining (0 disables inlining).
+    pub inline_max_depth: usize,
+    /// Specialize callees of specialized functions on their constant
+    /// arguments, following call chains up to this depth (0
+    /// disables).
+    pub call_specialization_depth: usize,
+    /// Maximum number of blocks in one specialized function.
+    pub max_blocks: usize,
+    /// Maximum number of SSA values in one specialized function.
+    pub max_values: usize,
+    /// Maximum number of contexts in one specialized function.
+    pub max_contexts: Option<usize>,
+    /// Maximum size in bytes of one specialized function body.
+    pub max_output_bytes: Option<usize>,
+    /// Maximum total size in bytes of all specialized function
+    /// bodies. Directives are kept in priority order until the
+    /// budget is exhausted; the rest are skipped.
+    pub max_total_bytes: Option<usize>,
+    /// Report directives that fail to specialize (with an error, not
+    /// only by exceeding the budget) and continue with the rest.
+    pub keep_going: bool,
+    /// Write the generic function's table index to the out-address
+    /// of each directive that fails to specialize. Implies
+    /// `keep_going`.
+    pub fallback_to_generic: bool,
+    /// Split the output's data segments wherever at least this many
+    /// bytes in a row need no initialization.
+    pub data_segment_gap: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            heap_memory: None,
+            stack_pointer: None,
+            function_table: None,
+            strip_custom_sections: vec![],
+            strip_intrinsics: false,
+            run_diff: false,
+            inline_max_insts: 0,
+            inline_max_depth: 4,
+            call_specialization_depth: 0,
+            max_blocks: 100_000,
+            max_values: 1_000_000,
+            max_contexts: None,
+            max_output_bytes: None,
+            max_total_bytes: None,
+            keep_going: false,
+            fallback_to_generic: false,
+            data_segment_gap: 16,
+        }
+    }
+}
+
+/// How far specialization has got: `done` of `total` directives.
+/// The total grows as call-site specializations are found.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// A progress callback. It may be called from several threads.
+pub type ProgressFn = dyn Fn(Progress) + Send + Sync;
+
+/// The result of partial evaluation.
+#[derive(Debug)]
+pub struct Output {
+    /// The output Wasm module.
+    pub bytes: Vec<u8>,
+    /// Stats for each generic function that was specialized.
+    pub stats: Vec<SpecializationStats>,
+    /// Directives that could not be specialized.
+    pub failures: Vec<Failure>,
+}
+
+/// A partial-evaluation run: set up the directives and options, then
+/// `run` it on a module.
+#[derive(Default)]
+pub struct Weval {
+    directives: Vec<Directive>,
+    directive_files: Vec<directive_file::DirectiveFile>,
+    budgets: Option<String>,
+    options: Options,
+    progress: Option<Box<ProgressFn>>,
+}
+
+impl Weval {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the options.
+    pub fn options(&mut self, options: Options) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Specialize according to `directive`, in addition to the
+    /// requests in the module itself.
+    pub fn directive(&mut self, directive: Directive) -> &mut Self {
+        self.directives.push(directive);
+        self
+    }
+
+    /// Specialize according to the directives in a file (see
+    /// `directive_file` for the format), in addition to the requests
+    /// in the module itself.
+    pub fn directive_file(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<&mut Self> {
+        let file = directive_file::DirectiveFile::read(path.as_ref())?;
+        self.directive_files.push(file);
+        Ok(self)
+    }
+
+    /// Apply per-function budget overrides, in the format of
+    /// `directive::apply_budgets`.
+    pub fn budgets(&mut self, budgets: &str) -> &mut Self {
+        self.budgets = Some(budgets.to_owned());
+        self
+    }
+
+    /// Report progress to `callback`.
+    pub fn progress(&mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> &mut Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Partially evaluate `input`.
+    pub fn run(&self, input: &[u8]) -> anyhow::Result<Output> {
+        let opts = &self.options;
+
+        // Load module.
+        let mut frontend_opts = waffle::FrontendOptions::default();
+        frontend_opts.debug = true;
+        let mut module = waffle::Module::from_wasm_bytes(input, &frontend_opts)?;
+
+        // If we're going to run the interpreter, we need to expand all
+        // functions.
+        if opts.run_diff {
+            module.expand_all_funcs()?;
+        }
+
+        // Build module image.
+        let root_names = image::RootNames {
+            stack_pointer: opts.stack_pointer.clone(),
+            heap: opts.heap_memory.clone(),
+            table: opts.function_table.clone(),
+        };
+        let mut im = image::build_image(&module, &root_names)?;
+
+        // Collect directives.
+        let mut directives = directive::collect(&module, &mut im)?;
+        directives.extend(self.directives.iter().cloned());
+        for file in &self.directive_files {
+            directives.extend(file.resolve(&module, &mut im)?);
+        }
+        if let Some(budgets) = &self.budgets {
+            directive::apply_budgets(&module, &mut directives[..], budgets)?;
+        }
+        log::debug!("Directives: {:?}", directives);
+
+        // Partially evaluate.
+        let mut result = eval::partially_evaluate(
+            module,
+            &mut im,
+            &directives[..],
+            opts,
+            self.progress.as_deref(),
+        )?;
+
+        // Update memories in module.
+        image::update(&mut result.module, &im, opts.data_segment_gap);
+
+        log::debug!("Final module:\n{}", result.module.display());
+
+        if let Some(mut orig_module) = result.orig_module.take() {
+            image::update(&mut orig_module, &im, opts.data_segment_gap);
+            diff::run_diff(orig_module, result.module.clone())?;
+        }
+
+        let mut new_funcs = result
+            .specialized
+            .iter()
+            .map(|(func, generic, lines)| dwarf::NewFunc {
+                func: func.index() as u32,
+                generic: generic.index() as u32,
+                name: result.module.funcs[*func].name().to_owned(),
+                lines: lines.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        // Compile the bodies left as IR (with `run_diff`, specialized
+        // and instrumented generic functions alike) here rather than
+        // in the backend, to describe them in the line tables too.
+        let mut compiled = vec![];
+        for (func, decl) in result.module.funcs.entries() {
+            if let FuncDecl::Body(_, name, body) = decl {
+                let (body, lines) = dwarf::compile_with_lines(&result.module, body, |_| None)?;
+                compiled.push((func, body));
+                match new_funcs.iter_mut().find(|f| f.func == func.index() as u32) {
+                    Some(new_func) => new_func.lines = lines,
+                    None => new_funcs.push(dwarf::NewFunc {
+                        func: func.index() as u32,
+                        generic: func.index() as u32,
+                        name: name.clone(),
+                        lines,
+                    }),
+                }
+            }
+        }
+        for (func, body) in compiled {
+            let decl = &mut result.module.funcs[func];
+            *decl = FuncDecl::Compiled(decl.sig(), decl.name().to_owned(), body);
+        }
+        let bytes = result.module.to_wasm_bytes()?;
+        let mut bytes = filter::merge_names(input, &bytes[..])?;
+        filter::append_custom_sections(input, &mut bytes, &opts.strip_custom_sections[..])?;
+        let strip_debug_info = opts
+            .strip_custom_sections
+            .iter()
+            .any(|name| dwarf::is_debug_section(name));
+        let bytes = if strip_debug_info {
+            bytes
+        } else {
+            dwarf::carry_over(input, bytes, &new_funcs[..], &[])?
+        };
+
+        let bytes = if opts.strip_intrinsics {
+            filter::filter(&bytes[..], &opts.strip_custom_sections[..])?
+        } else {
+            bytes
+        };
+
+        Ok(Output {
+            bytes,
+            stats: result.stats,
+            failures: result.failures,
+        })
+    }
+}
+
+/// Remove the weval intrinsics (imports and calls) from `module`,
+/// without specializing anything, and drop the custom sections named
+/// in `strip_custom_sections`.
+pub fn strip_intrinsics(
+    module: &[u8],
+    strip_custom_sections: &[String],
+) -> anyhow::Result<Vec<u8>> {
+    filter::filter(module, strip_custom_sections)
+}
@@ -1,22 +1,14 @@
-#![allow(dead_code)]
-
-use std::collections::VecDeque;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-mod directive;
-mod eval;
-mod filter;
-mod image;
-mod intrinsics;
-mod state;
-mod stats;
-mod value;
-
 const STUBS: &'static str = include_str!("../lib/weval-stubs.wat");
 
+/// Number of source lines, with the most runtime instructions, to
+/// show in the stats for each function.
+const MAX_STATS_LINES: usize = 20;
+
 #[derive(Clone, Debug, StructOpt)]
-pub struct Options {
+struct Options {
     /// The input Wasm module.
     #[structopt(short = "i")]
     input_module: PathBuf,
@@ -33,6 +25,31 @@ pub struct Options {
     #[structopt(long = "strip-intrinsics")]
     strip_intrinsics: bool,
 
+    /// The export name or name-section name of the memory used as
+    /// the program's heap (holding the request list, and what
+    /// pointers passed to intrinsics point into). Defaults to
+    /// `memory`, or else the only memory.
+    #[structopt(long = "heap-memory")]
+    heap_memory: Option<String>,
+
+    /// The export name or name-section name of the shadow-stack
+    /// pointer global. Defaults to `__stack_pointer`.
+    #[structopt(long = "stack-pointer")]
+    stack_pointer: Option<String>,
+
+    /// The export name or name-section name of the table holding
+    /// function pointers. Defaults to `__indirect_function_table`,
+    /// or else the only table.
+    #[structopt(long = "function-table")]
+    function_table: Option<String>,
+
+    /// Custom sections to drop from the output (may be repeated).
+    /// Other custom sections are kept, except `linking` and `reloc.*`,
+    /// which are always dropped. Naming any `.debug_*` section drops
+    /// all DWARF debug info.
+    #[structopt(long = "strip-custom-section")]
+    strip_custom_sections: Vec<String>,
+
     /// Run IR in interpreter differentially, before and after
     /// wevaling, comparing trace outputs.
     #[structopt(long = "run-diff")]
@@ -41,6 +58,78 @@ pub struct Options {
     /// Show stats on specialization code size.
     #[structopt(long = "show-stats")]
     show_stats: bool,
+
+    /// Inline direct calls to functions with at most this many
+    /// instructions before specializing (0 inlines only functions
+    /// marked with the `inline` intrinsic).
+    #[structopt(long = "inline-max-insts", default_value = "0")]
+    inline_max_insts: usize,
+
+    /// Maximum depth of nested inlining (0 disables inlining).
+    #[structopt(long = "inline-max-depth", default_value = "4")]
+    inline_max_depth: usize,
+
+    /// Specialize callees of specialized functions on their constant
+    /// arguments, following call chains up to this depth (0
+    /// disables).
+    #[structopt(long = "call-specialization-depth", default_value = "0")]
+    call_specialization_depth: usize,
+
+    /// Maximum number of blocks in one specialized function.
+    #[structopt(long = "max-blocks", default_value = "100000")]
+    max_blocks: usize,
+
+    /// Maximum number of SSA values in one specialized function.
+    #[structopt(long = "max-values", default_value = "1000000")]
+    max_values: usize,
+
+    /// Maximum number of contexts in one specialized function.
+    #[structopt(long = "max-contexts")]
+    max_contexts: Option<usize>,
+
+    /// Maximum size in bytes of one specialized function body.
+    #[structopt(long = "max-output-bytes")]
+    max_output_bytes: Option<usize>,
+
+    /// Maximum total size in bytes of all specialized function
+    /// bodies. Directives are kept in priority order until the
+    /// budget is exhausted; the rest are skipped.
+    #[structopt(long = "max-total-bytes")]
+    max_total_bytes: Option<usize>,
+
+    /// File with directives to specialize, in addition to those
+    /// requested in the module's memory (TOML; may be repeated). Each
+    /// `[[directive]]` names a function (`func`, by export name,
+    /// name-section name or index), its `args` (each of kind
+    /// `runtime`, `const` with a `type` and `value`, or `bytes` from a
+    /// `file` or inline `data`), and an `export` name and/or
+    /// `out-addr` for the result. Bytes are placed past the end of
+    /// the heap, where an allocator that has not started yet may
+    /// later claim them.
+    #[structopt(long = "directives")]
+    directive_files: Vec<PathBuf>,
+
+    /// File with per-function budget overrides: one line per
+    /// function, `<name or index> key=value ...`, with keys
+    /// `blocks`, `values`, `contexts`, `bytes` and `priority`.
+    #[structopt(long = "budget-file")]
+    budget_file: Option<PathBuf>,
+
+    /// Report directives that fail to specialize (with an error, not
+    /// only by exceeding the budget) and continue with the rest.
+    #[structopt(long = "keep-going")]
+    keep_going: bool,
+
+    /// Write the generic function's table index to the out-address
+    /// of each directive that fails to specialize. Implies
+    /// `--keep-going`.
+    #[structopt(long = "fallback-to-generic")]
+    fallback_to_generic: bool,
+
+    /// Split the output's data segments wherever at least this many
+    /// bytes in a row need no initialization.
+    #[structopt(long = "data-segment-gap", default_value = "16")]
+    data_segment_gap: usize,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -64,47 +153,59 @@ fn main() -> anyhow::Result<()> {
     };
 
     if opts.strip_intrinsics {
-        let bytes = filter::filter(&module_bytes[..])?;
+        let bytes = weval::strip_intrinsics(&module_bytes[..], &opts.strip_custom_sections[..])?;
         std::fs::write(&opts.output_module, &bytes[..])?;
         return Ok(());
     }
 
-    // Load module.
-    let mut frontend_opts = waffle::FrontendOptions::default();
-    frontend_opts.debug = true;
-    let mut module = waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts)?;
-
-    // If we're going to run the interpreter, we need to expand all
-    // functions.
-    if opts.run_diff {
-        module.expand_all_funcs()?;
+    let mut weval = weval::Weval::new();
+    weval.options(weval::Options {
+        heap_memory: opts.heap_memory.clone(),
+        stack_pointer: opts.stack_pointer.clone(),
+        function_table: opts.function_table.clone(),
+        strip_custom_sections: opts.strip_custom_sections.clone(),
+        // The wizened module calls the stubs; remove them again.
+        strip_intrinsics: opts.wizen,
+        run_diff: opts.run_diff,
+        inline_max_insts: opts.inline_max_insts,
+        inline_max_depth: opts.inline_max_depth,
+        call_specialization_depth: opts.call_specialization_depth,
+        max_blocks: opts.max_blocks,
+        max_values: opts.max_values,
+        max_contexts: opts.max_contexts,
+        max_output_bytes: opts.max_output_bytes,
+        max_total_bytes: opts.max_total_bytes,
+        keep_going: opts.keep_going,
+        fallback_to_generic: opts.fallback_to_generic,
+        data_segment_gap: opts.data_segment_gap,
+    });
+    for path in &opts.directive_files {
+        weval.directive_file(path)?;
+    }
+    if let Some(path) = &opts.budget_file {
+        weval.budgets(&std::fs::read_to_string(path)?);
     }
-
-    // Build module image.
-    let mut im = image::build_image(&module)?;
-
-    // Collect directives.
-    let directives = directive::collect(&module, &mut im)?;
-    log::debug!("Directives: {:?}", directives);
-
-    // Partially evaluate.
     let progress = indicatif::ProgressBar::new(0);
-    let mut result =
-        eval::partially_evaluate(module, &mut im, &directives[..], &opts, Some(progress))?;
-
-    // Update memories in module.
-    image::update(&mut result.module, &im);
-
-    log::debug!("Final module:\n{}", result.module.display());
+    weval.progress(move |p| {
+        progress.set_length(p.total as u64);
+        progress.set_position(p.done as u64);
+    });
+
+    let output = weval.run(&module_bytes[..])?;
+
+    for failure in &output.failures {
+        eprintln!(
+            "Failed to specialize {} ({:?}): {}",
+            failure.directive.func, failure.directive.const_params, failure.reason
+        );
+    }
 
     if opts.run_diff {
-        image::update(result.orig_module.as_mut().unwrap(), &im);
-        run_diff(result.orig_module.unwrap(), result.module);
         return Ok(());
     }
 
     if opts.show_stats {
-        for stats in result.stats {
+        for stats in output.stats {
             eprintln!(
                 "Function {}: {} blocks, {} insts)",
                 stats.generic, stats.generic_blocks, stats.generic_insts,
@@ -121,108 +222,13 @@ fn main() -> anyhow::Result<()> {
             for (bucket, (blocks, insts)) in buckets {
                 eprintln!(" * bucket {:?}: {} blocks, {} insts", bucket, blocks, insts);
             }
-        }
-    }
-
-    let bytes = result.module.to_wasm_bytes()?;
-
-    let bytes = if opts.wizen {
-        filter::filter(&bytes[..])?
-    } else {
-        bytes
-    };
-
-    std::fs::write(&opts.output_module, &bytes[..])?;
-
-    Ok(())
-}
-
-struct TraceIter {
-    thread: std::thread::JoinHandle<()>,
-    channel: std::sync::mpsc::Receiver<(usize, Vec<waffle::ConstVal>)>,
-}
-
-impl TraceIter {
-    fn new(module: waffle::Module<'static>) -> TraceIter {
-        let mut ctx = waffle::InterpContext::new(&module).unwrap();
-        if let Some(start) = module.start_func {
-            ctx.call(&module, start, &[]).ok().unwrap();
-        }
-
-        let entry = if let Some(waffle::Export {
-            kind: waffle::ExportKind::Func(func),
-            ..
-        }) = module.exports.iter().find(|e| &e.name == "_start")
-        {
-            *func
-        } else {
-            panic!("No _start entrypoint");
-        };
-
-        let (sender, receiver) = std::sync::mpsc::sync_channel(1000);
-        let thread = std::thread::spawn(move || {
-            let count = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
-            let count_cloned = count.clone();
-            ctx.trace_handler = Some(Box::new(move |id, args| {
-                count_cloned.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                sender.send((id, args)).is_ok()
-            }));
-            let result = ctx.call(&module, entry, &[]);
-            if let Err(e) = result.ok() {
-                eprintln!(
-                    "Panic after {} steps: {:?}",
-                    count.load(std::sync::atomic::Ordering::Relaxed),
-                    e
-                );
-                let handler = ctx.trace_handler.unwrap();
-                handler(0, vec![]);
+            for (pos, insts) in stats.runtime_insts_by_line.iter().take(MAX_STATS_LINES) {
+                eprintln!(" * {}: {} runtime insts", pos, insts);
             }
-        });
-
-        TraceIter {
-            thread,
-            channel: receiver,
         }
     }
-}
 
-impl Iterator for TraceIter {
-    type Item = (usize, Vec<waffle::ConstVal>);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.channel.recv().ok()
-    }
-}
-
-fn run_diff(orig_module: waffle::Module<'_>, wevaled_module: waffle::Module<'_>) {
-    let orig_text = format!("{}", orig_module.display());
-    let wevaled_text = format!("{}", wevaled_module.display());
-    let orig = TraceIter::new(orig_module.without_orig_bytes());
-    let wevaled = TraceIter::new(wevaled_module.without_orig_bytes());
-
-    let mut progress: u64 = 0;
-    let mut last_n = VecDeque::new();
-    for ((orig_id, orig_args), (wevaled_id, wevaled_args)) in orig.zip(wevaled) {
-        progress += 1;
-        if progress % 100000 == 0 {
-            eprintln!("{} steps", progress);
-        }
-
-        last_n.push_back((orig_id, orig_args.clone()));
-        if last_n.len() > 10 {
-            last_n.pop_front();
-        }
+    std::fs::write(&opts.output_module, &output.bytes[..])?;
 
-        if orig_id != wevaled_id || orig_args != wevaled_args {
-            eprintln!("Original:\n{}\n", orig_text);
-            eprintln!("wevaled:\n{}\n", wevaled_text);
-            eprintln!("Recent tracepoints:");
-            for (id, args) in last_n {
-                eprintln!("* {}, {:?}", id, args);
-            }
-            panic!(
-                "Mismatch: orig ({}, {:?}), wevaled ({}, {:?})",
-                orig_id, orig_args, wevaled_id, wevaled_args
-            );
-        }
-    }
+    Ok(())
 }
@@ -40,7 +40,7 @@ use fxhash::FxHashMap as HashMap;
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 use waffle::entity::{EntityRef, EntityVec, PerEntity};
-use waffle::{Block, FunctionBody, Global, Type, Value};
+use waffle::{Block, FunctionBody, Global, Memory, Type, Value};
 
 waffle::declare_entity!(Context, "context");
 
@@ -64,6 +64,10 @@ pub struct Contexts {
 }
 
 impl Contexts {
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
     pub fn create(&mut self, parent: Option<Context>, elem: ContextElem) -> Context {
         let parent = parent.unwrap_or(Context::invalid());
         match self.dedup.entry((parent, elem)) {
@@ -108,8 +112,38 @@ pub struct ProgPointState {
     pub regs: BTreeMap<u64, RegValue>,
     /// Global values.
     pub globals: BTreeMap<Global, AbstractValue>,
+    /// Memory overlay: values stored to known addresses whose stores
+    /// have been elided. Memory contents at these addresses are
+    /// stale until the entry is flushed; any address *not* in the
+    /// overlay is up-to-date in memory.
+    pub mem: BTreeMap<MemAddr, RegValue>,
+}
+
+/// An address tracked by the memory overlay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MemAddr {
+    pub memory: Memory,
+    /// Base pointer of a symbolic region (see
+    /// `AbstractValue::SymbolicPtr`), or `None` for an absolute
+    /// address.
+    pub base: Option<Value>,
+    /// Absolute address, or offset from `base`.
+    pub addr: u32,
 }
 
+impl MemAddr {
+    /// Does an access of `size` bytes at this address overlap an
+    /// access of `other_size` bytes at `other`?
+    pub fn overlaps(&self, size: u32, other: &MemAddr, other_size: u32) -> bool {
+        self.memory == other.memory
+            && self.base == other.base
+            && (self.addr as u64) < (other.addr as u64 + other_size as u64)
+            && (other.addr as u64) < (self.addr as u64 + size as u64)
+    }
+}
+
+/// A value carried in a specialization register or in the memory
+/// overlay. Values that reach a merge point become blockparams.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum RegValue {
     Value {
@@ -125,7 +159,16 @@ pub enum RegValue {
 }
 
 impl RegValue {
-    fn meet(a: &RegValue, b: &RegValue) -> RegValue {
+    /// Meet two values, taking the hull of any bounds, or widening
+    /// them if `widen` is set.
+    fn meet(a: &RegValue, b: &RegValue, widen: bool) -> RegValue {
+        let meet_abs = |a: &AbstractValue, b: &AbstractValue| {
+            if widen {
+                AbstractValue::widen(a, b)
+            } else {
+                AbstractValue::meet(a, b)
+            }
+        };
         match (a, b) {
             (a, b) if a == b => a.clone(),
             (
@@ -137,14 +180,14 @@ impl RegValue {
                 },
             ) if ty1 == ty2 => RegValue::Merge {
                 ty: *ty1,
-                abs: AbstractValue::meet(abs1, abs2),
+                abs: meet_abs(abs1, abs2),
             },
             (RegValue::Merge { ty: ty1, abs: abs1 }, RegValue::Merge { ty: ty2, abs: abs2 })
                 if ty1 == ty2 =>
             {
                 RegValue::Merge {
                     ty: *ty1,
-                    abs: AbstractValue::meet(abs1, abs2),
+                    abs: meet_abs(abs1, abs2),
                 }
             }
             (
@@ -160,7 +203,7 @@ impl RegValue {
                 RegValue::Merge { ty, abs },
             ) if ty == ty1 => RegValue::Merge {
                 ty: *ty,
-                abs: AbstractValue::meet(abs, abs1),
+                abs: meet_abs(abs, abs1),
             },
             _ => {
                 log::trace!("Values {:?} and {:?} meeting to Conflict", a, b);
@@ -169,6 +212,13 @@ impl RegValue {
         }
     }
 
+    fn abs(&self) -> Option<&AbstractValue> {
+        match self {
+            RegValue::Value { abs, .. } | RegValue::Merge { abs, .. } => Some(abs),
+            RegValue::Conflict => None,
+        }
+    }
+
     pub fn value(&self) -> Option<Value> {
         match self {
             RegValue::Value { data, .. } => Some(*data),
@@ -271,24 +321,59 @@ impl ProgPointState {
         ProgPointState {
             regs: BTreeMap::new(),
             globals,
+            mem: BTreeMap::new(),
         }
     }
 
-    pub fn meet_with(&mut self, other: &ProgPointState) -> bool {
+    /// Meet `other` into this state. Bounds take the hull, or are
+    /// widened if `widen` is set (once they have kept changing, so
+    /// that loops converge). Returns whether the state changed, and
+    /// whether any bounds did.
+    pub fn meet_with(&mut self, other: &ProgPointState, widen: bool) -> (bool, bool) {
+        let bounds_changed = std::cell::Cell::new(false);
+        let note_bounds = |old: Option<&AbstractValue>, new: Option<&AbstractValue>| {
+            if old != new && matches!(new, Some(AbstractValue::Bounded(..))) {
+                bounds_changed.set(true);
+            }
+        };
+        let reg_meet = |a: &RegValue, b: &RegValue| {
+            let met = RegValue::meet(a, b, widen);
+            note_bounds(a.abs(), met.abs());
+            met
+        };
+        let abs_meet = |a: &AbstractValue, b: &AbstractValue| {
+            let met = if widen {
+                AbstractValue::widen(a, b)
+            } else {
+                AbstractValue::meet(a, b)
+            };
+            note_bounds(Some(a), Some(&met));
+            met
+        };
+
         let mut changed = false;
-        changed |= map_meet_with(&mut self.regs, &other.regs, RegValue::meet, None);
+        changed |= map_meet_with(&mut self.regs, &other.regs, reg_meet, None);
 
         changed |= map_meet_with(
             &mut self.globals,
             &other.globals,
-            AbstractValue::meet,
+            abs_meet,
             Some(AbstractValue::Runtime(None, ValueTags::default())),
         );
-        changed
+        // An overlay entry present on only some incoming paths
+        // becomes a `Conflict`: it is flushed on the edges that have
+        // it, and memory is authoritative again in the merged state.
+        changed |= map_meet_with(
+            &mut self.mem,
+            &other.mem,
+            reg_meet,
+            Some(RegValue::Conflict),
+        );
+        (changed, bounds_changed.get())
     }
 
     pub fn update_across_edge(&mut self) {
-        for value in self.regs.values_mut() {
+        for value in self.regs.values_mut().chain(self.mem.values_mut()) {
             if let RegValue::Value { ty, abs, .. } = value {
                 // Ensure all specialization-register values become
                 // blockparams, even if only one pred.
@@ -310,28 +395,56 @@ impl ProgPointState {
         get_blockparam: &mut GB,
         remove_blockparam: &mut RB,
     ) -> anyhow::Result<()> {
-        let mut to_remove = vec![];
-        for (&idx, value) in &mut self.regs {
-            match value {
-                RegValue::Value { .. } => {}
-                RegValue::Merge { ty, abs } => {
-                    let param = get_blockparam(ctx, idx, *ty);
-                    *value = RegValue::Value {
-                        data: param,
-                        ty: *ty,
-                        abs: abs.clone(),
-                    };
-                }
-                RegValue::Conflict => {
-                    remove_blockparam(ctx, idx);
-                    to_remove.push(idx);
-                }
+        update_map_at_block_entry(&mut self.regs, ctx, get_blockparam, remove_blockparam);
+        Ok(())
+    }
+
+    pub fn update_mem_at_block_entry<
+        C,
+        GB: FnMut(&mut C, MemAddr, Type) -> Value,
+        RB: FnMut(&mut C, MemAddr),
+    >(
+        &mut self,
+        ctx: &mut C,
+        get_blockparam: &mut GB,
+        remove_blockparam: &mut RB,
+    ) -> anyhow::Result<()> {
+        update_map_at_block_entry(&mut self.mem, ctx, get_blockparam, remove_blockparam);
+        Ok(())
+    }
+}
+
+fn update_map_at_block_entry<
+    K: Copy + Ord,
+    C,
+    GB: FnMut(&mut C, K, Type) -> Value,
+    RB: FnMut(&mut C, K),
+>(
+    map: &mut BTreeMap<K, RegValue>,
+    ctx: &mut C,
+    get_blockparam: &mut GB,
+    remove_blockparam: &mut RB,
+) {
+    let mut to_remove = vec![];
+    for (&idx, value) in map.iter_mut() {
+        match value {
+            RegValue::Value { .. } => {}
+            RegValue::Merge { ty, abs } => {
+                let param = get_blockparam(ctx, idx, *ty);
+                *value = RegValue::Value {
+                    data: param,
+                    ty: *ty,
+                    abs: abs.clone(),
+                };
+            }
+            RegValue::Conflict => {
+                remove_blockparam(ctx, idx);
+                to_remove.push(idx);
             }
         }
-        for to_remove in to_remove {
-            self.regs.remove(&to_remove);
-        }
-        Ok(())
+    }
+    for to_remove in to_remove {
+        map.remove(&to_remove);
     }
 }
 
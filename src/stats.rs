@@ -1,10 +1,11 @@
 //! Post-specialization stats.
 
+use crate::diagnostic::SourcePos;
 use crate::state::{Context, Contexts};
 use fxhash::FxHashSet;
 use std::collections::BTreeMap;
-use waffle::entity::PerEntity;
-use waffle::{Block, Func, FunctionBody};
+use waffle::entity::{EntityRef, PerEntity};
+use waffle::{Block, Func, FunctionBody, Module, Operator, SourceLoc, ValueDef};
 
 #[derive(Clone, Debug, Default)]
 pub struct SpecializationStats {
@@ -15,6 +16,13 @@ pub struct SpecializationStats {
     pub specialized_blocks: usize,
     pub specialized_insts: usize,
     pub blocks_and_insts_by_bucket: BTreeMap<Option<u32>, (usize, usize)>,
+    /// Instructions left to run at runtime (other than constants) in
+    /// the specializations, by source location in the generic
+    /// function.
+    pub runtime_insts_by_loc: BTreeMap<SourceLoc, usize>,
+    /// The same, grouped by source line (where the input has DWARF
+    /// line info), most first.
+    pub runtime_insts_by_line: Vec<(SourcePos, usize)>,
 }
 
 impl SpecializationStats {
@@ -27,6 +35,19 @@ impl SpecializationStats {
         ret
     }
 
+    /// Group `runtime_insts_by_loc` by source line.
+    pub fn resolve_lines(&mut self, module: &Module) {
+        let mut lines: BTreeMap<SourcePos, usize> = BTreeMap::new();
+        for (&loc, &insts) in &self.runtime_insts_by_loc {
+            if let Some(pos) = SourcePos::lookup(module, loc) {
+                *lines.entry(pos.line_only()).or_insert(0) += insts;
+            }
+        }
+        let mut lines = lines.into_iter().collect::<Vec<_>>();
+        lines.sort_by_key(|(_pos, insts)| std::cmp::Reverse(*insts));
+        self.runtime_insts_by_line = lines;
+    }
+
     pub fn add_specialization(
         &mut self,
         body: &FunctionBody,
@@ -43,6 +64,25 @@ impl SpecializationStats {
                 .or_insert((0, 0));
             pair.0 += 1;
             pair.1 += insts;
+
+            for &inst in &body.blocks[block].insts {
+                let loc = body.source_locs[inst];
+                if loc.is_invalid() {
+                    continue;
+                }
+                if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                    if matches!(
+                        op,
+                        Operator::I32Const { .. }
+                            | Operator::I64Const { .. }
+                            | Operator::F32Const { .. }
+                            | Operator::F64Const { .. }
+                    ) {
+                        continue;
+                    }
+                    *self.runtime_insts_by_loc.entry(loc).or_insert(0) += 1;
+                }
+            }
         });
         self.specialized_blocks += blocks;
         self.specialized_insts += insts;
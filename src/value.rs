@@ -1,5 +1,9 @@
 //! Symbolic and concrete values.
 
+use crate::bounds::Bounds;
+use waffle::entity::EntityRef;
+use waffle::Memory;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum WasmVal {
     I32(u32),
@@ -10,12 +14,12 @@ pub enum WasmVal {
 }
 
 impl WasmVal {
-    pub fn is_truthy(self) -> bool {
+    pub fn is_truthy(self) -> anyhow::Result<bool> {
         match self {
-            WasmVal::I32(i) => i != 0,
+            WasmVal::I32(i) => Ok(i != 0),
             // Only boolean-ish types (i32) can be evaluated for
             // truthiness.
-            _ => panic!("Type error: non-i32 used in boolean-ish context"),
+            _ => anyhow::bail!("Type error: non-i32 {:?} used in boolean-ish context", self),
         }
     }
 
@@ -27,6 +31,16 @@ impl WasmVal {
         }
     }
 
+    pub fn ty(self) -> waffle::Type {
+        match self {
+            WasmVal::I32(_) => waffle::Type::I32,
+            WasmVal::I64(_) => waffle::Type::I64,
+            WasmVal::F32(_) => waffle::Type::F32,
+            WasmVal::F64(_) => waffle::Type::F64,
+            WasmVal::V128(_) => waffle::Type::V128,
+        }
+    }
+
     pub fn from_bits(ty: waffle::Type, bits: u64) -> Option<Self> {
         match ty {
             waffle::Type::I32 => Some(WasmVal::I32(bits as u32)),
@@ -66,25 +80,59 @@ pub enum AbstractValue {
     /// A value only computed at runtime. The instruction that
     /// computed it is specified, if known.
     Runtime(Option<waffle::Value>, ValueTags),
+    /// An integer value only computed at runtime, but known to be
+    /// within some bounds (an interval and/or known bits).
+    Bounded(Bounds, ValueTags),
+    /// A pointer into a region created by the `make.symbolic.ptr`
+    /// intrinsic: the base pointer (a value in the specialized
+    /// function) plus a constant offset. Memory contents of the region
+    /// are virtualized into SSA values during specialization and are
+    /// only written back at `flush.to.mem` (or when leaving the
+    /// function).
+    SymbolicPtr(waffle::Value, u32),
+    /// A runtime value (a value in the specialized function) plus a
+    /// constant offset, e.g. an address relative to the shadow-stack
+    /// pointer. Unlike with `SymbolicPtr`, memory addressed this way
+    /// is not virtualized.
+    Offset(waffle::Value, u32),
 }
 
+/// Tags on a value. The const-memory tags are per memory: each of
+/// the first `MAX_TAGGED_MEMORIES` memories has a pair of bits, so
+/// that a pointer known to point to constant data in one memory does
+/// not make loads from another memory at the same address constant.
+/// They take the low 16 bits; the rest are free for other tags.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct ValueTags(u32);
 
+/// Memories beyond these can't be tagged as constant.
+pub const MAX_TAGGED_MEMORIES: usize = 8;
+
+/// The `const_memory` bits of all memories.
+const CONST_MEMORY_BITS: u32 = 0x5555;
+
 /// Constructors for value tags.
 ///
 /// N.B.: the constant values returned below are *bitfields*, i.e.,
 /// powers of two.
 impl ValueTags {
-    /// All values reached in memory through this value as a pointer
-    /// are constant at specialization time.
-    pub const fn const_memory() -> Self {
-        ValueTags(1)
+    /// All values reached in `memory` through this value as a
+    /// pointer are constant at specialization time.
+    pub fn const_memory(memory: Memory) -> Self {
+        Self::memory_bits(memory, 1)
     }
 
     /// `const_memory` is passed transitively through loads.
-    pub const fn const_memory_transitive() -> Self {
-        ValueTags(2)
+    pub fn const_memory_transitive(memory: Memory) -> Self {
+        Self::memory_bits(memory, 2)
+    }
+
+    fn memory_bits(memory: Memory, bits: u32) -> Self {
+        if memory.index() < MAX_TAGGED_MEMORIES {
+            ValueTags(bits << (2 * memory.index()))
+        } else {
+            ValueTags(0)
+        }
     }
 }
 
@@ -99,9 +147,26 @@ impl ValueTags {
     pub fn contains(&self, tags: ValueTags) -> bool {
         (self.0 & tags.0) == tags.0
     }
+    /// Whether loads from `memory` through this value as a pointer
+    /// may be constant-folded.
+    pub fn is_const_memory(&self, memory: Memory) -> bool {
+        let tags = ValueTags::const_memory(memory);
+        tags.0 != 0 && self.contains(tags)
+    }
+    /// Whether values loaded from `memory` through this value are
+    /// themselves pointers to constant memory.
+    pub fn is_const_memory_transitive(&self, memory: Memory) -> bool {
+        let tags = ValueTags::const_memory_transitive(memory);
+        tags.0 != 0 && self.contains(tags)
+    }
+    /// The tags for a pointer derived from this one by arithmetic:
+    /// still pointing to constant memory, but not transitively.
+    pub fn derived_ptr(&self) -> ValueTags {
+        ValueTags(self.0 & CONST_MEMORY_BITS)
+    }
     pub fn meet(&self, other: ValueTags) -> ValueTags {
         // - const_memory and const_memory_transitive merge as intersection.
-        ValueTags((self.0 & 3) & (other.0 & 3))
+        ValueTags(self.0 & other.0)
     }
     /// Get the tags that are "sticky": propagate across all ops.
     pub fn sticky(&self) -> ValueTags {
@@ -115,6 +180,9 @@ impl AbstractValue {
             &AbstractValue::Top => ValueTags::default(),
             &AbstractValue::Concrete(_, t) => t,
             &AbstractValue::Runtime(_, t) => t,
+            &AbstractValue::Bounded(_, t) => t,
+            &AbstractValue::SymbolicPtr(..) => ValueTags::default(),
+            &AbstractValue::Offset(..) => ValueTags::default(),
         }
     }
 
@@ -123,6 +191,9 @@ impl AbstractValue {
             &AbstractValue::Top => AbstractValue::Top,
             &AbstractValue::Concrete(k, t) => AbstractValue::Concrete(k, t | new_tags),
             &AbstractValue::Runtime(v, t) => AbstractValue::Runtime(v, t | new_tags),
+            &AbstractValue::Bounded(b, t) => AbstractValue::Bounded(b, t | new_tags),
+            &AbstractValue::SymbolicPtr(base, off) => AbstractValue::SymbolicPtr(base, off),
+            &AbstractValue::Offset(base, off) => AbstractValue::Offset(base, off),
         }
     }
 
@@ -145,7 +216,51 @@ impl AbstractValue {
             (AbstractValue::Runtime(cause1, t1), x) | (x, AbstractValue::Runtime(cause1, t1)) => {
                 AbstractValue::Runtime(*cause1, t1.meet(x.tags()))
             }
-            (av1, av2) => AbstractValue::Runtime(None, av1.tags().meet(av2.tags())),
+            (av1, av2) => match (av1.int_bounds(), av2.int_bounds()) {
+                (Some(b1), Some(b2)) if b1.bits == b2.bits => {
+                    AbstractValue::from_bounds(b1.meet(&b2), av1.tags().meet(av2.tags()), None)
+                }
+                _ => AbstractValue::Runtime(None, av1.tags().meet(av2.tags())),
+            },
+        }
+    }
+
+    /// Meet `next` into `prev`, widening bounds that grew. Used where
+    /// values merge around loops, so that bounds cannot keep growing
+    /// one step per iteration.
+    pub fn widen(prev: &AbstractValue, next: &AbstractValue) -> AbstractValue {
+        let met = AbstractValue::meet(prev, next);
+        match (prev.int_bounds(), &met) {
+            (Some(b1), AbstractValue::Bounded(b2, t)) if b1.bits == b2.bits => {
+                AbstractValue::from_bounds(b1.widen(b2), *t, None)
+            }
+            _ => met,
+        }
+    }
+
+    /// The abstract value for an integer within `bounds`: a constant
+    /// if the bounds allow only one value, and `Runtime` if they
+    /// allow any.
+    pub fn from_bounds(
+        bounds: Bounds,
+        tags: ValueTags,
+        cause: Option<waffle::Value>,
+    ) -> AbstractValue {
+        match bounds.as_constant() {
+            Some(k) if bounds.bits == 32 => AbstractValue::Concrete(WasmVal::I32(k as u32), tags),
+            Some(k) => AbstractValue::Concrete(WasmVal::I64(k), tags),
+            None if bounds.is_full() => AbstractValue::Runtime(cause, tags),
+            None => AbstractValue::Bounded(bounds, tags),
+        }
+    }
+
+    /// Bounds known for an integer value, if any.
+    pub fn int_bounds(&self) -> Option<Bounds> {
+        match *self {
+            AbstractValue::Concrete(WasmVal::I32(k), _) => Some(Bounds::constant(32, k as u64)),
+            AbstractValue::Concrete(WasmVal::I64(k), _) => Some(Bounds::constant(64, k)),
+            AbstractValue::Bounded(b, _) => Some(b),
+            _ => None,
         }
     }
 
@@ -167,7 +282,30 @@ impl AbstractValue {
         }
     }
 
+    pub fn is_symbolic_ptr(&self) -> Option<(waffle::Value, u32)> {
+        match self {
+            &AbstractValue::SymbolicPtr(base, off) => Some((base, off)),
+            _ => None,
+        }
+    }
+
+    /// The runtime base and constant offset of this value, if it is
+    /// known as one; `value` is the value itself in the specialized
+    /// function, which is its own base.
+    pub fn base_offset(&self, value: waffle::Value) -> Option<(waffle::Value, u32)> {
+        match *self {
+            AbstractValue::Runtime(..) => Some((value, 0)),
+            AbstractValue::Offset(base, off) | AbstractValue::SymbolicPtr(base, off) => {
+                Some((base, off))
+            }
+            _ => None,
+        }
+    }
+
     pub fn is_const_truthy(&self) -> Option<bool> {
-        self.is_const_u32().map(|k| k != 0)
+        match *self {
+            AbstractValue::Bounded(b, _) if b.bits == 32 && b.lo > 0 => Some(true),
+            _ => self.is_const_u32().map(|k| k != 0),
+        }
     }
 }